@@ -0,0 +1,11 @@
+// The kernel only builds for aarch64, the modules that don't touch the hardware
+// are pulled in from its tree so their tests can run here.
+#![allow(dead_code)]
+
+extern crate alloc;
+
+#[path = "../../kernel/src/errno.rs"]
+mod errno;
+
+#[path = "../../kernel/src/process/scheduler.rs"]
+mod scheduler;
@@ -0,0 +1,38 @@
+// the numbers are the same as Linux, user space gets them negated
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(isize)]
+pub enum Errno {
+    EPERM        = 1,
+    ENOENT       = 2,
+    ESRCH        = 3,
+    EINTR        = 4,
+    E2BIG        = 7,
+    ENOEXEC      = 8,
+    EBADF        = 9,
+    ECHILD       = 10,
+    EAGAIN       = 11,
+    ENOMEM       = 12,
+    EACCES       = 13,
+    EFAULT       = 14,
+    EEXIST       = 17,
+    ENODEV       = 19,
+    ENOTDIR      = 20,
+    EISDIR       = 21,
+    EINVAL       = 22,
+    EMFILE       = 24,
+    EFBIG        = 27,
+    ENOSPC       = 28,
+    EPIPE        = 32,
+    ERANGE       = 34,
+    ENAMETOOLONG = 36,
+    ENOSYS       = 38,
+    ELOOP        = 40,
+    ETIMEDOUT    = 110,
+}
+
+impl Errno {
+    // what the system call returns in x0
+    pub fn to_return(self) -> usize {
+        -(self as isize) as usize
+    }
+}
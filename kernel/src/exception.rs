@@ -1,7 +1,13 @@
 use crate::common::*;
+use crate::errno::Errno;
 use crate::process;
 use crate::gic;
 use crate::serial;
+use crate::timer;
+use crate::smp;
+use crate::irq;
+use crate::uaccess;
+use crate::process::signal::{self, SIGSEGV, SIGBUS, SIGILL, SIGTRAP, SIGFPE};
 
 extern "C" {
     fn byebye();
@@ -23,15 +29,32 @@ extern "C" fn handle_sync(uctx: &mut UserContext, es: usize, fa: usize) {
 
     match es >> 26 {
         0b010101 => {
-            uctx.x[0] = crate::syscall::SYSCALL_TABLE[es & 0xffff](uctx)
-                                .unwrap_or_else(|err| err as usize);
+            // system calls are interruptible, see irq.rs
+            irq::enable();
+            uctx.x[0] = match crate::syscall::SYSCALL_TABLE.get(es & 0xffff) {
+                Some(syscall) => syscall(uctx).unwrap_or_else(Errno::to_return),
+                None => Errno::ENOSYS.to_return(),
+            };
+            irq::disable();
         }
-        0b100100 => {
-            page_fault_handler(es, fa, uctx.elr_el1);
+        0b100100 | 0b100000 => {
+            // data or instruction abort
+            if let Err(sig) = page_fault_handler(es, fa) {
+                user_fault(uctx, sig, es, fa);
+            }
         }
-        _ => {
-            panic!("pid: {}, ESR: {:x}, ELR:{:x}", process::current().pid, es, uctx.elr_el1);
+        0b000111 => {
+            // FP/SIMD access trapped by CPACR_EL1
+            process::fpsimd_trap();
         }
+        // PC or SP alignment fault
+        0b100010 | 0b100110 => user_fault(uctx, SIGBUS, es, fa),
+        // BRK, breakpoint, software step, watchpoint
+        0b111100 | 0b110000 | 0b110010 | 0b110100 => user_fault(uctx, SIGTRAP, es, fa),
+        // trapped floating-point exception
+        0b101100 => user_fault(uctx, SIGFPE, es, fa),
+        // undefined instruction, illegal execution state and everything we don't expect from EL0
+        _ => user_fault(uctx, SIGILL, es, fa),
     }
     
     assert_eq!(uctx.poison, POISON_VALUE);
@@ -40,14 +63,33 @@ extern "C" fn handle_sync(uctx: &mut UserContext, es: usize, fa: usize) {
 
 #[no_mangle]
 extern "C" fn handle_int(irq: u32) {
-    match irq {
+    // the upper bits of an SGI tell the sender
+    match irq & 0x3ff {
         30 => unsafe {
-                let x = 1_000_000_usize;
-                asm!("msr CNTP_TVAL_EL0, {}", in(reg) x);
                 ((GICCBASE + 0x10) as *mut u32).write(irq);
-                // context switch
-                process::yield_cpu();
+                timer::run();
+                if timer::slice_expired() {
+                    // context switch, the scheduler arms the timer for the next process
+                    process::yield_cpu();
+                } else {
+                    timer::rearm();
+                }
             },
+        _ => handle_device_int(irq),
+    }
+
+    unsafe {
+        gic::irq_eoi(irq);
+    }
+
+    back_to_earth();
+}
+
+fn handle_device_int(irq: u32) {
+    match irq & 0x3ff {
+        // another cpu put something on our run queue,
+        // the scheduler will look at it when the time slice ends
+        smp::SGI_WAKEUP => {}
         33 => {
             serial::SerialPort::new().receive();
         }
@@ -59,33 +101,163 @@ extern "C" fn handle_int(irq: u32) {
         }
         _ => panic!("unrecognized irq number {}", irq),
     }
+}
+
+fn acknowledge() -> u32 {
+    unsafe {
+        ((GICCBASE + 0x0C) as *const u32).read_volatile()
+    }
+}
+
+// the scheduler is idle and woken up by an interrupt,
+// there is no process to switch from or return to
+pub fn handle_idle_int() {
+    let irq = acknowledge();
+
+    match irq & 0x3ff {
+        // spurious
+        1023 => return,
+        30 => {
+            timer::run();
+            timer::rearm();
+        }
+        _ => handle_device_int(irq),
+    }
 
     unsafe {
         gic::irq_eoi(irq);
     }
+}
 
-    back_to_earth();
+// taken in EL1 by an interruptible section, see irq.rs.
+// the process isn't switched away here, it may be in the middle of anything.
+#[no_mangle]
+extern "C" fn handle_kernel_int() {
+    let irq = acknowledge();
+
+    match irq & 0x3ff {
+        1023 => return,
+        30 => {
+            timer::run();
+            if timer::slice_expired() {
+                // quiet until the scheduler starts the next one
+                timer::stop_slice();
+                process::set_need_resched();
+            } else {
+                timer::rearm();
+            }
+        }
+        _ => handle_device_int(irq),
+    }
+
+    unsafe {
+        gic::irq_eoi(irq);
+    }
+}
+
+// returns where to go on
+#[no_mangle]
+extern "C" fn handle_kernel_sync(es: usize, fa: usize, elr: usize) -> usize {
+    if es >> 26 == 0b100101 && uaccess::access_ok(fa, 1) {
+        // the kernel touches user memory, the fault is handled as if user space did it
+        if page_fault_handler(es, fa).is_ok() {
+            return elr;
+        }
+        // a bad pointer from user space
+        if let Some(fixup) = uaccess::fixup(elr) {
+            return fixup;
+        }
+    }
+    panic!("kernel exception on cpu{}, ESR: {:x}, ELR: {:x}, FAR: {:x}",
+           smp::cpu_id(), es, elr, fa);
+}
+
+fn describe(sig: usize) -> &'static str {
+    match sig {
+        SIGSEGV => "segmentation fault",
+        SIGBUS  => "bus error",
+        SIGILL  => "illegal instruction",
+        SIGTRAP => "trace trap",
+        SIGFPE  => "floating point exception",
+        _       => "fault",
+    }
+}
+
+// the process can't go on from where it is, it's killed unless it catches the signal.
+// the other processes don't notice.
+fn user_fault(uctx: &UserContext, sig: usize, es: usize, fa: usize) {
+    if signal::force(sig) {
+        println!("pid {}: {} at 0x{:x}, ESR: {:x}, FAR: {:x}",
+                 process::current().pid, describe(sig), uctx.elr_el1, es, fa);
+    }
 }
 
+// the order of the entries in exception_table
+const VECTOR_SOURCE: [&str; 4] = ["EL1 with SP_EL0", "EL1 with SP_EL1", "EL0 in AArch64", "EL0 in AArch32"];
+const VECTOR_KIND: [&str; 4] = ["sync exception", "IRQ", "FIQ", "SError"];
+
+// the asynchronous error type in ESR_EL1.AET
+fn serror_type(es: usize) -> &'static str {
+    const ISS_IDS: usize = 1 << 24;
+    if es & ISS_IDS != 0 {
+        return "implementation defined";
+    }
+    match (es >> 10) & 0b111 {
+        0b000 => "uncontainable",
+        0b001 => "unrecoverable",
+        0b010 => "restartable",
+        0b011 => "recoverable",
+        0b110 => "corrected",
+        _ => "reserved",
+    }
+}
+
+// nothing is set up to take FIQ, and an SError means the hardware is unhappy,
+// neither can be returned from
 #[no_mangle]
-extern "C" fn page_fault_handler(es: usize, fault_addr: usize, elr: usize) {
-    assert!((es >> 26) == 0b100100 || (es >> 26) == 0b100101);
-    let proc = process::current();
-    if fault_addr < proc.heap_end() {
-        // heap page fault
-        proc.page_tb().create(round_down(fault_addr), PAGESIZE, "rw")
-                        .expect("Can't handle page fault");
-    } else {
-        // FIXME: Segmentation fault ?
-        panic!("page fault: from: {}\n fault addr: 0x{:x}\n at: 0x{:x}", match es >> 26 {
-            0b100100 => "user",
-            0b100101 => "kernel",
-            _ => "???",
-        }, fault_addr, elr);
+extern "C" fn handle_bad_exception(kind: usize, es: usize, elr: usize, fa: usize, spsr: usize) -> ! {
+    let source = VECTOR_SOURCE[kind >> 2];
+    let what = VECTOR_KIND[kind & 0b11];
+    match process::current_pid() {
+        Some(pid) => println!("unexpected {} from {} on cpu{}, pid {}", what, source, smp::cpu_id(), pid),
+        None => println!("unexpected {} from {} on cpu{}", what, source, smp::cpu_id()),
+    }
+    println!("    ESR: {:x}, ELR: {:x}, FAR: {:x}, SPSR: {:x}", es, elr, fa, spsr);
+    if kind & 0b11 == 3 {
+        println!("    {} error", serror_type(es));
+    }
+    panic!("{} from {}", what, source);
+}
+
+// ISS of data and instruction abort
+const ISS_WNR:          usize = 1 << 6;  // caused by writing, data abort only
+const ISS_DFSC:         usize = 0x3f;    // fault status code
+const DFSC_ADDR_SIZE:   usize = 0b0000_00;
+const DFSC_TRANSLATION: usize = 0b0001_00;
+const DFSC_ACCESS_FLAG: usize = 0b0010_00;
+const DFSC_PERMISSION:  usize = 0b0011_00;
+
+fn is_write(es: usize) -> bool {
+    matches!(es >> 26, 0b100100 | 0b100101) && es & ISS_WNR != 0
+}
+
+// a fault on a user page, Err with the signal if the access isn't allowed
+fn page_fault_handler(es: usize, fault_addr: usize) -> Result<(), usize> {
+    match (es & ISS_DFSC) & !0b11 {
+        DFSC_TRANSLATION | DFSC_ACCESS_FLAG | DFSC_PERMISSION => {
+            process::page_fault(fault_addr, is_write(es), es >> 26 == 0b100000)
+        }
+        DFSC_ADDR_SIZE => Err(SIGSEGV),
+        // alignment, external aborts and the like
+        _ => Err(SIGBUS),
     }
 }
 
 pub fn back_to_earth() -> ! {
+    // the time slice may have ended in the system call
+    process::cond_resched();
+    process::signal::deliver();
+
     unsafe {
         byebye();
     }
@@ -0,0 +1,70 @@
+// the FP/SIMD registers of user space are switched lazily.
+// a process starts with EL0 access trapped, its registers are set up on the first use
+// and from then on saved and restored along with it.
+// the kernel itself is built without FP/SIMD, so EL1 never traps.
+
+// CPACR_EL1.FPEN
+const FPEN_SHIFT: usize = 20;
+const FPEN_TRAP_EL0: usize = 0b01 << FPEN_SHIFT;
+const FPEN_NO_TRAP:  usize = 0b11 << FPEN_SHIFT;
+
+extern "C" {
+    fn fpsimd_save(state: *mut FpState);
+    fn fpsimd_restore(state: *const FpState);
+}
+
+#[repr(C, align(16))]
+#[derive(Clone, Copy)]
+pub struct FpState {
+    // q0 - q31
+    q: [[u64; 2]; 32],
+    fpcr: u64,
+    fpsr: u64,
+}
+
+impl FpState {
+    // what a process sees on its first use
+    pub const fn new() -> Self {
+        Self {
+            q: [[0; 2]; 32],
+            fpcr: 0,
+            fpsr: 0,
+        }
+    }
+
+    // the registers are live on this cpu
+    pub fn save(&mut self) {
+        unsafe {
+            fpsimd_save(self);
+        }
+    }
+
+    // load the registers and let EL0 use them
+    pub fn restore(&self) {
+        unsafe {
+            fpsimd_restore(self);
+        }
+        set_cpacr(FPEN_NO_TRAP);
+    }
+}
+
+fn set_cpacr(cpacr: usize) {
+    unsafe {
+        asm!("msr CPACR_EL1, {}",
+             "isb", in(reg) cpacr);
+    }
+}
+
+// whether EL0 may be using the registers right now
+pub fn is_live() -> bool {
+    let cpacr: usize;
+    unsafe {
+        asm!("mrs {}, CPACR_EL1", out(reg) cpacr);
+    }
+    cpacr & FPEN_NO_TRAP == FPEN_NO_TRAP
+}
+
+// the next use of FP/SIMD in EL0 traps
+pub fn trap_el0() {
+    set_cpacr(FPEN_TRAP_EL0);
+}
@@ -2,60 +2,91 @@ use alloc::collections::btree_map::BTreeMap;
 use core::mem::MaybeUninit;
 use crate::{fs::BLOCK_SIZE, process};
 use alloc::boxed::Box;
+use alloc::vec::Vec;
 use crate::virtio;
+use crate::timer;
+use spin::{Mutex, MutexGuard};
 
-static mut BUFFERS: MaybeUninit<BTreeMap<u32, Buffer>> = MaybeUninit::uninit();
+static mut BUFFERS: MaybeUninit<Mutex<BTreeMap<u32, Box<Buffer>>>> = MaybeUninit::uninit();
+
+// in seconds
+const FLUSH_INTERVAL: u64 = 5;
 
 pub struct Buffer {
     blockno: u32,
     pub busy: bool, // is this buffer in disk r/w operation ?
+    dirty: bool,    // has it been written since it was last flushed ?
     data: Box<[u8; 1024]>,
 }
 
 pub fn init() {
     unsafe {
-        BUFFERS = MaybeUninit::new(BTreeMap::new());
+        BUFFERS = MaybeUninit::new(Mutex::new(BTreeMap::new()));
+    }
+}
+
+fn buffers() -> MutexGuard<'static, BTreeMap<u32, Box<Buffer>>> {
+    unsafe {
+        BUFFERS.assume_init_ref().lock()
     }
 }
 
 impl Buffer {
+    // the buffers are boxed and never evicted, so they can outlive the lock
     pub unsafe fn read(blockno: u32) -> &'static mut Self {
-        let buffers = BUFFERS.assume_init_mut();
+        let mut buffers = buffers();
 
         match buffers.contains_key(&blockno) {
             true => {
-                let buffer = buffers.get_mut(&blockno).unwrap();
-                if buffer.busy { // this buffer is in disk r/w operation
-                    process::sleep(buffer.as_ptr() as usize);
+                let buffer = &mut **buffers.get_mut(&blockno).unwrap() as *mut Buffer;
+                while (*buffer).busy { // this buffer is in disk r/w operation
+                    process::sleep_on((*buffer).as_ptr() as usize, buffers);
+                    buffers = self::buffers();
                 }
-                buffer
+                &mut *buffer
             }
             false => {
                 let buffer = {
-                    buffers.insert(blockno, Self {
+                    buffers.insert(blockno, Box::new(Self {
                         blockno,
                         busy: true,
+                        dirty: false,
                         data: Box::new([0; 1024]),
-                    });
-                    buffers.get_mut(&blockno).unwrap()
+                    }));
+                    &mut **buffers.get_mut(&blockno).unwrap() as *mut Buffer
                 };
+                drop(buffers);
 
-                virtio::disk_rw(buffer, false);
-                buffer
+                virtio::disk_rw(&mut *buffer, false);
+
+                // let the others waiting for it in
+                let buffers = self::buffers();
+                (*buffer).busy = false;
+                drop(buffers);
+                process::wakeup((*buffer).as_ptr() as usize);
+                &mut *buffer
             }
         }
     }
 
     pub fn write(&mut self, pos: usize, buf: &[u8]) {
         assert!(pos + buf.len() <= BLOCK_SIZE);
-        self.data[pos..buf.len()].copy_from_slice(buf);
+        self.dirty = true;
+        self.data[pos..pos + buf.len()].copy_from_slice(buf);
+    }
+
+    // it has been changed through a pointer from `as_ptr`
+    pub fn mark_dirty(&mut self) {
+        self.dirty = true;
     }
 
     pub fn as_ptr(&self) -> *const [u8; 1024] {
         self.data.as_ptr() as *const [u8; 1024]
     }
 
+    // the caller is going to write to it
     pub fn as_mut_ptr(&mut self) -> *mut [u8; 1024] {
+        self.dirty = true;
         self.data.as_mut_ptr() as *mut [u8; 1024]
     }
 
@@ -63,3 +94,37 @@ impl Buffer {
         self.blockno
     }
 }
+
+// write the dirty buffers back to the disk
+pub fn sync() {
+    let dirty = buffers().values_mut()
+                            .filter(|buffer| buffer.dirty && !buffer.busy)
+                            .map(|buffer| {
+                                buffer.dirty = false;
+                                buffer.busy = true;
+                                &mut **buffer as *mut Buffer
+                            })
+                            .collect::<Vec<_>>();
+
+    for buffer in dirty {
+        unsafe {
+            virtio::disk_rw(&mut *buffer, true);
+
+            let buffers = buffers();
+            (*buffer).busy = false;
+            drop(buffers);
+            process::wakeup((*buffer).as_ptr() as usize);
+        }
+    }
+}
+
+// kernel thread
+pub fn flusher() {
+    loop {
+        let deadline = timer::now() + timer::from_secs(FLUSH_INTERVAL);
+        // nobody wakes it up, it just waits for the deadline
+        process::sleep_timeout(flusher as usize, deadline);
+        super::pagecache::sync();
+        sync();
+    }
+}
@@ -1,95 +1,179 @@
 use alloc::boxed::Box;
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Mutex;
 use crate::print;
-use super::{*, inode::Inode};
+use super::{*, pagecache};
+use crate::errno::Errno::{self, EBADF};
 use crate::process;
 
+// An open file is shared by every descriptor that refers to it,
+// e.g. the descriptors inherited by a forked child,
+// so it may be used on several cpus at once.
+// It's released when the last reference is dropped.
 pub struct File {
-    pos: usize,
-    flags: usize,
+    pos: Mutex<FilePos>,
+    flags: AtomicUsize,
+    // The operation may sleep, so no lock is held across it.
+    // It takes care of its own state, like the pipe.
     op: Box<dyn FileOperation>,
+    // the inode number of a file on the disk
+    inode: Option<u32>,
+}
+
+struct FilePos {
+    offset: usize,
+    // an operation is using the offset, the others sleep until it's done
+    busy: bool,
 }
 
 impl File {
-    pub fn new(inode: &'static mut Inode, flags: usize) -> Self {
-        Self {
-            pos: 0,
-            flags,
-            op: Box::new(inode),
-        }
+    pub fn new(inode: &'static mut Inode, flags: usize) -> Arc<Self> {
+        let num = inode.num;
+        Self::create(Box::new(InodeFile(num)), Some(num), flags)
+    }
+
+    pub fn stdio() -> Arc<Self> {
+        Self::with_op(Box::new(Stdio), FLAGS_O_RDWR)
+    }
+
+    pub fn with_op(op: Box<dyn FileOperation>, flags: usize) -> Arc<Self> {
+        Self::create(op, None, flags)
     }
 
-    pub fn stdio() -> Self {
-        Self {
-            pos: 0,
-            flags: FLAGS_O_RDWR,
-            op: Box::new(Stdio),
+    fn create(op: Box<dyn FileOperation>, inode: Option<u32>, flags: usize) -> Arc<Self> {
+        Arc::new(Self {
+            pos: Mutex::new(FilePos { offset: 0, busy: false }),
+            flags: AtomicUsize::new(flags & !FLAGS_O_CLOEXEC),
+            op,
+            inode,
+        })
+    }
+
+    pub fn write(&self, s: &[u8]) -> Result<usize, Errno> {
+        if self.flags() & FLAGS_O_RDONLY != 0 {
+            return Err(EBADF);
         }
+
+        let flags = self.flags() & FLAGS_STATUS_MASK;
+        self.with_offset(|offset| self.op.write(offset, s, flags))
     }
 
-    pub fn write(&mut self, s: &[u8]) -> Result<usize, isize> {
-        if self.flags & FLAGS_O_RDONLY != 0 {
-            return Err(-1);
+    pub fn read(&self, buf: &mut [u8]) -> Result<usize, Errno> {
+        if self.flags() & FLAGS_O_WRONLY != 0 {
+            return Err(EBADF);
         }
-        self.op.write(&mut self.pos, s)
+
+        let flags = self.flags() & FLAGS_STATUS_MASK;
+        self.with_offset(|offset| self.op.read(offset, buf, flags))
     }
 
-    pub fn read(&mut self, buf: &mut [u8]) -> Result<usize, isize> {
-        if self.flags & FLAGS_O_WRONLY != 0 {
-            return Err(-1);
+    // the operations on a file on the disk take turns, so none of the offset updates is lost.
+    // pipes and the console have no offset, a reader waiting for input doesn't hold up a writer.
+    fn with_offset<F: FnOnce(&mut usize) -> Result<usize, Errno>>(&self, f: F) -> Result<usize, Errno> {
+        if self.inode.is_none() {
+            return f(&mut 0);
         }
-        self.op.read(&mut self.pos, buf)
+
+        let channel = &self.pos as *const _ as usize;
+        let mut pos = self.pos.lock();
+        while pos.busy {
+            process::sleep_on(channel, pos);
+            pos = self.pos.lock();
+        }
+        pos.busy = true;
+        let mut offset = pos.offset;
+        drop(pos);
+
+        let res = f(&mut offset);
+
+        let mut pos = self.pos.lock();
+        pos.offset = offset;
+        pos.busy = false;
+        drop(pos);
+        process::wakeup(channel);
+        res
+    }
+
+    pub fn inode(&self) -> Option<u32> {
+        self.inode
     }
 
     pub fn flags(&self) -> usize {
-        self.flags
+        self.flags.load(Ordering::Relaxed)
+    }
+
+    // only the status flags can be changed, the access mode stays the same
+    pub fn set_flags(&self, flags: usize) {
+        self.flags.store((self.flags() & !FLAGS_STATUS_MASK) | (flags & FLAGS_STATUS_MASK), Ordering::Relaxed);
+    }
+}
+
+// An entry of the file descriptor table
+#[derive(Clone)]
+pub struct FileDesc {
+    pub file: Arc<File>,
+    pub cloexec: bool,
+}
+
+impl FileDesc {
+    pub fn new(file: Arc<File>, cloexec: bool) -> Self {
+        Self { file, cloexec }
     }
 }
 
 pub struct Stdio;
 
-impl FileOperation for &mut Inode {
-    fn write(&mut self, offset: &mut usize, buf: &[u8]) -> Result<usize, isize> {
-        self.iter_mut().skip(*offset)
-                        .take(buf.len())
-                        .enumerate()
-                        .for_each(|(i, c)| {
-                            *c = buf[i];
-                        });
-        Ok(buf.len())
+// a file on the disk, it's looked up by the inode number every time
+struct InodeFile(u32);
+
+impl FileOperation for InodeFile {
+    fn write(&self, offset: &mut usize, buf: &[u8], _: usize) -> Result<usize, Errno> {
+        let inode = unsafe { get_inode(self.0) };
+        let len = match inode.is_file() {
+            true  => pagecache::write(inode, *offset, buf)?,
+            false => {
+                let _meta = lock_meta();
+                inode.write_at(*offset, buf)
+            }
+        };
+        *offset += len;
+        Ok(len)
     }
 
-    fn read(&mut self, offset: &mut usize, buf: &mut [u8]) -> Result<usize, isize> {
-        let mut len = 0;
-        self.iter().enumerate()
-                    .skip(*offset)
-                    .take(buf.len())
-                    .for_each(|(i, &c)| {
-                        buf[i] = c;
-                        len += 1;
-                    });
+    fn read(&self, offset: &mut usize, buf: &mut [u8], _: usize) -> Result<usize, Errno> {
+        let inode = unsafe { get_inode(self.0) };
+        let len = match inode.is_file() {
+            true  => pagecache::read(inode, *offset, buf)?,
+            false => inode.read_at(*offset, buf),
+        };
         *offset += len;
         Ok(len)
     }
 }
 
 impl FileOperation for Stdio {
-    fn write(&mut self, _: &mut usize, s: &[u8]) -> Result<usize, isize> {
+    fn write(&self, _: &mut usize, s: &[u8], _: usize) -> Result<usize, Errno> {
         print!("{}", unsafe {core::str::from_utf8_unchecked(s) });
         Ok(s.len())
     }
 
-    fn read(&mut self, _: &mut usize, buf: &mut [u8]) -> Result<usize, isize> {
+    fn read(&self, _: &mut usize, buf: &mut [u8], _: usize) -> Result<usize, Errno> {
         process::get_user_input(buf)
     }
 }
 
+// `flags` are the status flags of the file, like O_NONBLOCK.
+// several cpus may be in the same operation at once.
 pub trait FileOperation {
-    fn write(&mut self,
+    fn write(&self,
             offset: &mut usize,
-            buf: &[u8]
-        ) -> Result<usize, isize>;
-    fn read(&mut self,
+            buf: &[u8],
+            flags: usize
+        ) -> Result<usize, Errno>;
+    fn read(&self,
             offset: &mut usize,
-            buf: &mut [u8]
-        ) -> Result<usize, isize>;
+            buf: &mut [u8],
+            flags: usize
+        ) -> Result<usize, Errno>;
 }
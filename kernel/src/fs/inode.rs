@@ -1,4 +1,5 @@
 use super::buffer::Buffer;
+use super::BLOCK_SIZE;
 
 pub const INODE_TYPE_DIR:  u8 = 0;
 const INODE_TYPE_FILE: u8 = 1;
@@ -14,6 +15,9 @@ pub struct Inode {
 }
 
 impl Inode {
+    // 12 direct blocks and one indirect block
+    pub const MAX_SIZE: usize = (12 + BLOCK_SIZE / 4) * BLOCK_SIZE;
+
     pub fn is_file(&self) -> bool {
         self.ty == INODE_TYPE_FILE
     }
@@ -43,7 +47,40 @@ impl Inode {
     }
 
     pub fn resize(&mut self, new: u32) {
-        self.size = new;
+        if self.size != new {
+            self.size = new;
+            self.mark_dirty();
+        }
+    }
+
+    // it has been changed, the inode is in a block of its own
+    pub fn mark_dirty(&self) {
+        unsafe {
+            Buffer::read(self.num).mark_dirty();
+        }
+    }
+
+    // straight from the buffers, regular files go through the page cache
+    pub fn read_at(&self, pos: usize, buf: &mut [u8]) -> usize {
+        let mut len = 0;
+        self.iter().skip(pos)
+                    .take(buf.len())
+                    .for_each(|&c| {
+                        buf[len] = c;
+                        len += 1;
+                    });
+        len
+    }
+
+    // it grows the file if it goes past the end
+    pub fn write_at(&mut self, pos: usize, buf: &[u8]) -> usize {
+        self.iter_mut().skip(pos)
+                        .take(buf.len())
+                        .enumerate()
+                        .for_each(|(i, c)| {
+                            *c = buf[i];
+                        });
+        buf.len()
     }
 }
 
@@ -191,15 +228,18 @@ impl<'a> Iterator for InodeDataIterMut<'a> {
             let tmp = if idx < 12 { idx } else { 12 };
             if self.inode.addr[tmp] == 0 {
                 self.inode.addr[tmp] = super::get_empty_block().expect("Disk is full");
+                self.inode.mark_dirty();
             }
 
             let blockno = {
                 match idx {
                     0..=11 => self.inode.addr[idx],
                     _ => unsafe {
-                        let ptr = (Buffer::read(self.inode.addr[12]).as_mut_ptr() as *mut u32).add(idx - 12);
+                        let indirect = Buffer::read(self.inode.addr[12]);
+                        let ptr = (indirect.as_ptr() as *mut u32).add(idx - 12);
                         if ptr.read() == 0 {
                             ptr.write(super::get_empty_block().expect("Disk is full"));
+                            indirect.mark_dirty();
                         }
                         ptr.read()
                     }
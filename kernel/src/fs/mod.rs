@@ -4,11 +4,15 @@ use self::inode::*;
 pub mod file;
 pub mod buffer;
 pub mod inode;
+pub mod pagecache;
+pub mod pipe;
 pub mod superblock;
 
 use buffer::Buffer;
 use file::*;
+use crate::errno::Errno::{self, EEXIST, EINVAL, EISDIR, ENAMETOOLONG, ENOENT, ENOSPC, ENOTDIR};
 use crate::process;
+use spin::Mutex;
 
 pub const BLOCK_SIZE: usize = 1024;
 
@@ -16,30 +20,60 @@ pub const FLAGS_O_RDONLY:    usize = 1;
 pub const FLAGS_O_WRONLY:    usize = 2;
 pub const FLAGS_O_RDWR:      usize = 4;
 pub const FLAGS_O_DIRECTORY: usize = 8;
+pub const FLAGS_O_CLOEXEC:   usize = 16;
+pub const FLAGS_O_NONBLOCK:  usize = 32;
+
+// flags that can be changed by fcntl(F_SETFL)
+pub const FLAGS_STATUS_MASK: usize = FLAGS_O_NONBLOCK;
+
+// changes to the metadata take turns: allocating blocks, growing an inode, mkdir.
+// they may sleep on the disk, so the others sleep until it's no longer busy.
+static META_BUSY: Mutex<bool> = Mutex::new(false);
 
 pub fn init() {
     buffer::init();
+    pagecache::init();
+}
+
+// released when it's dropped
+pub struct MetaGuard;
+
+pub fn lock_meta() -> MetaGuard {
+    let channel = &META_BUSY as *const _ as usize;
+    let mut busy = META_BUSY.lock();
+    while *busy {
+        process::sleep_on(channel, busy);
+        busy = META_BUSY.lock();
+    }
+    *busy = true;
+    MetaGuard
 }
 
+impl Drop for MetaGuard {
+    fn drop(&mut self) {
+        *META_BUSY.lock() = false;
+        process::wakeup(&META_BUSY as *const _ as usize);
+    }
+}
+
+// looking an inode up doesn't dirty its block, `Inode::mark_dirty` does
 pub unsafe fn get_inode(inode_num: u32) -> &'static mut Inode {
-    &mut  *(Buffer::read(inode_num).as_mut_ptr() as *mut Inode)
+    &mut  *(Buffer::read(inode_num).as_ptr() as *mut Inode)
 }
 
 pub unsafe fn get_root_inode() -> &'static mut Inode {
-    &mut *(Buffer::read(get_superblock().get_root_inode_num())
-                    .as_mut_ptr() as *mut Inode)
+    get_inode(get_superblock().get_root_inode_num())
 }
 
 pub unsafe fn get_superblock() -> &'static Superblock {
     &*(Buffer::read(0).as_ptr() as *const Superblock)
 }
 
-unsafe fn get_bitmap() -> &'static mut [u8; 1024] {
-    &mut *(Buffer::read(get_superblock().get_bitmap_block_num()))
-                    .as_mut_ptr()
+unsafe fn get_bitmap() -> &'static mut Buffer {
+    Buffer::read(get_superblock().get_bitmap_block_num())
 }
 
-pub fn path_lookup(path: &str) -> Result<&'static mut Inode, isize>{
+pub fn path_lookup(path: &str) -> Result<&'static mut Inode, Errno>{
     let mut inode = unsafe {
         if path.starts_with('.') || !path.starts_with('/') {
             // current working directory
@@ -52,55 +86,79 @@ pub fn path_lookup(path: &str) -> Result<&'static mut Inode, isize>{
 
     for name in path.split('/').filter(|name| !name.is_empty()) {
         if inode.is_file() {
-            return Err(-1);
+            return Err(ENOTDIR);
         }
 
-        for entry in inode.dirent() {
-            if entry.match_name(name) {
-                inode = unsafe { get_inode(entry.inode_num()) };
-                break;
+        // only the root has a dirent for itself, and it's the only one without ".."
+        inode = match name {
+            "." => inode,
+            ".." => unsafe { get_inode(inode.parent) },
+            _ => {
+                let num = inode.dirent()
+                                .find(|entry| entry.match_name(name))
+                                .ok_or(ENOENT)?
+                                .inode_num();
+                unsafe { get_inode(num) }
             }
-        }
+        };
     }
     Ok(inode)
 }
 
-pub fn open(path: &[u8], flags: usize) -> Result<&mut Inode, isize> {
-    let path = core::str::from_utf8(path).unwrap();
+pub fn open(path: &[u8], flags: usize) -> Result<&'static mut Inode, Errno> {
+    // it comes from user space
+    let path = core::str::from_utf8(path).map_err(|_| ENOENT)?;
 
     let inode = path_lookup(path)?;
 
     if inode.is_dir() && (flags & FLAGS_O_DIRECTORY) == 0 {
-        return Err(-1);
+        return Err(EISDIR);
     }
 
     Ok(inode)
 }
 
-pub fn read(file: &mut File, buf: &mut [u8]) -> Result<usize, isize> {
+pub fn read(file: &File, buf: &mut [u8]) -> Result<usize, Errno> {
     file.read(buf)
 }
 
-pub fn write(file: &mut File, s: &[u8]) -> Result<usize, isize> {
+pub fn write(file: &File, s: &[u8]) -> Result<usize, Errno> {
     file.write(s)
 }
 
-pub fn mkdir(path: &[u8]) -> Result<usize, isize> {
-    let path = core::str::from_utf8(path).unwrap();
-    let mut dir = path_lookup(path)?;
+pub fn mkdir(path: &[u8]) -> Result<usize, Errno> {
+    let path = core::str::from_utf8(path).map_err(|_| EINVAL)?;
+    // nobody else can take the name or the block meanwhile
+    let _meta = lock_meta();
+    if path_lookup(path).is_ok() {
+        return Err(EEXIST);
+    }
+
+    let path = path.trim_end_matches('/');
+    let (parent, name) = match path.rfind('/') {
+        Some(idx) => (&path[..=idx], &path[idx + 1..]),
+        None => ("", path),
+    };
+    let dir = path_lookup(parent)?;
+    if dir.is_file() {
+        return Err(ENOTDIR);
+    }
+    // the name is nul-terminated in the dirent
+    if name.len() >= 12 {
+        return Err(ENAMETOOLONG);
+    }
 
     if dir.size() + 16 > 1024 {
         // A dirent is 16 bytes.
         // Directory size is limited under a block, which is 1024 bytes
-        return Err(-1);
+        return Err(ENOSPC);
     }
 
     // find an empty block
-    let inode_block = get_empty_block().ok_or(-1_isize)?;
-    let name = path.split('/').last().ok_or(-1_isize)?;
+    let inode_block = get_empty_block().ok_or(ENOSPC)?;
     let dirent = Dirent::new(inode_block, name.as_ref());
 
-    dir.write(&mut (dir.size() as usize), dirent.as_ref())?;
+    dir.write_at(dir.size() as usize, dirent.as_ref());
     let mut new_inode = Inode {
         ty: INODE_TYPE_DIR,
         num: inode_block,
@@ -110,7 +168,7 @@ pub fn mkdir(path: &[u8]) -> Result<usize, isize> {
     };
 
     let dirent = Dirent::new(dir.num, "..".as_bytes());
-    (&mut new_inode).write(&mut 0, dirent.as_ref())?;
+    new_inode.write_at(0, dirent.as_ref());
 
     unsafe {
         Buffer::read(inode_block).write(0, new_inode.as_ref())
@@ -118,14 +176,17 @@ pub fn mkdir(path: &[u8]) -> Result<usize, isize> {
     Ok(0)
 }
 
+// with `lock_meta` held
 fn get_empty_block() -> Option<u32> {
+    let bitmap = unsafe { get_bitmap() };
     let res = unsafe {
-        get_bitmap().iter_mut()
+        (*(bitmap.as_ptr() as *mut [u8; 1024])).iter_mut()
                     .enumerate()
                     .find(|(_, v)| **v != 0xff)?
     };
 
     let num = Some(res.0 as u32 * 8 + res.1.trailing_ones());
     *res.1 = *res.1 | (*res.1 + 1);
+    bitmap.mark_dirty();
     num
 }
\ No newline at end of file
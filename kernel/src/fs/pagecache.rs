@@ -0,0 +1,158 @@
+use alloc::collections::btree_map::BTreeMap;
+use alloc::vec::Vec;
+use core::alloc::{GlobalAlloc, Layout};
+use core::mem::MaybeUninit;
+use spin::{Mutex, MutexGuard};
+use crate::common::*;
+use crate::errno::Errno::{self, EFBIG, ENOMEM};
+use crate::mm::frame;
+use crate::process;
+use super::{get_inode, inode::Inode};
+
+// 4 KiB pages of the regular files, by the inode number and the index of the page.
+// mmap maps the frames into user space, so they are counted like user pages,
+// the cache holds a reference of its own.
+// like the buffers, they are never evicted.
+static mut PAGES: MaybeUninit<Mutex<BTreeMap<(u32, usize), Page>>> = MaybeUninit::uninit();
+
+struct Page {
+    pa: usize,
+    busy: bool,  // is it being read in ?
+    dirty: bool, // has it been written since it was last written back ?
+}
+
+pub fn init() {
+    unsafe {
+        PAGES = MaybeUninit::new(Mutex::new(BTreeMap::new()));
+    }
+}
+
+fn pages() -> MutexGuard<'static, BTreeMap<(u32, usize), Page>> {
+    unsafe {
+        PAGES.assume_init_ref().lock()
+    }
+}
+
+// the frame of page `idx` of the file, it's read in the first time
+pub fn get(inode: u32, idx: usize) -> Result<usize, Errno> {
+    let mut pages = pages();
+    loop {
+        match pages.get(&(inode, idx)) {
+            Some(page) if page.busy => {
+                let channel = page.pa;
+                process::sleep_on(channel, pages);
+                pages = self::pages();
+            }
+            Some(page) => return Ok(page.pa),
+            None => break,
+        }
+    }
+
+    let pa = unsafe {
+        crate::ALLOCATOR.alloc(Layout::from_size_align_unchecked(PAGESIZE, 4))
+    } as usize;
+    if pa == 0 {
+        return Err(ENOMEM);
+    }
+    frame::init_ref(pa);
+    pages.insert((inode, idx), Page { pa, busy: true, dirty: false });
+    drop(pages);
+
+    // the frame is zeroed, so is whatever is past the end of the file
+    let buf = unsafe { core::slice::from_raw_parts_mut(pa as *mut u8, PAGESIZE) };
+    unsafe { get_inode(inode) }.read_at(idx * PAGESIZE, buf);
+
+    // let the others waiting for it in
+    let mut pages = self::pages();
+    pages.get_mut(&(inode, idx)).unwrap().busy = false;
+    drop(pages);
+    process::wakeup(pa);
+    Ok(pa)
+}
+
+// it's going to be written through a shared mapping
+pub fn mark_dirty(inode: u32, idx: usize) {
+    if let Some(page) = pages().get_mut(&(inode, idx)) {
+        page.dirty = true;
+    }
+}
+
+pub fn read(inode: &Inode, pos: usize, buf: &mut [u8]) -> Result<usize, Errno> {
+    let size = inode.size() as usize;
+    if pos >= size {
+        return Ok(0);
+    }
+
+    let len = buf.len().min(size - pos);
+    let mut done = 0;
+    while done < len {
+        let (idx, offset) = ((pos + done) / PAGESIZE, (pos + done) % PAGESIZE);
+        let n = (PAGESIZE - offset).min(len - done);
+        let pa = get(inode.num, idx)?;
+        unsafe {
+            core::ptr::copy_nonoverlapping((pa + offset) as *const u8, buf[done..].as_mut_ptr(), n);
+        }
+        done += n;
+    }
+    Ok(len)
+}
+
+// the file grows if it goes past the end, the blocks are allocated on writeback
+pub fn write(inode: &mut Inode, pos: usize, buf: &[u8]) -> Result<usize, Errno> {
+    if buf.is_empty() {
+        return Ok(0);
+    }
+    if pos >= Inode::MAX_SIZE {
+        return Err(EFBIG);
+    }
+
+    let len = buf.len().min(Inode::MAX_SIZE - pos);
+    let mut done = 0;
+    while done < len {
+        let (idx, offset) = ((pos + done) / PAGESIZE, (pos + done) % PAGESIZE);
+        let n = (PAGESIZE - offset).min(len - done);
+        let pa = get(inode.num, idx)?;
+        unsafe {
+            core::ptr::copy_nonoverlapping(buf[done..].as_ptr(), (pa + offset) as *mut u8, n);
+        }
+        mark_dirty(inode.num, idx);
+        done += n;
+    }
+
+    let _meta = super::lock_meta();
+    if pos + len > inode.size() as usize {
+        inode.resize((pos + len) as u32);
+    }
+    Ok(len)
+}
+
+// write the dirty pages back into the buffers, the flusher takes them to the disk
+pub fn sync() {
+    let dirty = pages().iter_mut()
+                        .filter(|(_, page)| page.dirty && !page.busy)
+                        .map(|(&key, page)| {
+                            page.dirty = false;
+                            (key, page.pa)
+                        })
+                        .collect::<Vec<_>>();
+
+    for ((num, idx), pa) in dirty {
+        // the blocks are allocated here
+        let _meta = super::lock_meta();
+        let inode = unsafe { get_inode(num) };
+        let pos = idx * PAGESIZE;
+        let size = inode.size() as usize;
+        if pos < size {
+            let buf = unsafe {
+                core::slice::from_raw_parts(pa as *const u8, (size - pos).min(PAGESIZE))
+            };
+            inode.write_at(pos, buf);
+        }
+
+        // a writable shared mapping doesn't fault on every write,
+        // it's written back again as long as it's mapped
+        if frame::get_ref(pa) > 1 {
+            mark_dirty(num, idx);
+        }
+    }
+}
@@ -0,0 +1,148 @@
+use alloc::boxed::Box;
+use alloc::collections::vec_deque::VecDeque;
+use alloc::sync::Arc;
+use spin::Mutex;
+use super::*;
+use crate::errno::Errno::{self, EAGAIN, EBADF, EINTR, EPIPE};
+use crate::process;
+
+const PIPE_SIZE: usize = 4096;
+
+struct Pipe {
+    buffer: VecDeque<u8>,
+    readers: usize,
+    writers: usize,
+}
+
+impl Pipe {
+    // channel that the readers sleep on
+    fn read_channel(pipe: &Arc<Mutex<Pipe>>) -> usize {
+        Arc::as_ptr(pipe) as usize
+    }
+
+    // channel that the writers sleep on
+    fn write_channel(pipe: &Arc<Mutex<Pipe>>) -> usize {
+        Arc::as_ptr(pipe) as usize + 1
+    }
+}
+
+pub struct PipeReader(Arc<Mutex<Pipe>>);
+pub struct PipeWriter(Arc<Mutex<Pipe>>);
+
+// create a pipe, returns the read end and the write end.
+// `flags` are the status flags of both, like O_NONBLOCK
+pub fn pipe(flags: usize) -> (Arc<File>, Arc<File>) {
+    let pipe = Arc::new(Mutex::new(Pipe {
+        buffer: VecDeque::with_capacity(PIPE_SIZE),
+        readers: 1,
+        writers: 1,
+    }));
+
+    let flags = flags & FLAGS_STATUS_MASK;
+    let reader = File::with_op(Box::new(PipeReader(pipe.clone())), FLAGS_O_RDONLY | flags);
+    let writer = File::with_op(Box::new(PipeWriter(pipe)), FLAGS_O_WRONLY | flags);
+    (reader, writer)
+}
+
+impl FileOperation for PipeReader {
+    fn write(&self, _: &mut usize, _: &[u8], _: usize) -> Result<usize, Errno> {
+        Err(EBADF)
+    }
+
+    fn read(&self, _: &mut usize, buf: &mut [u8], flags: usize) -> Result<usize, Errno> {
+        loop {
+            let mut pipe = self.0.lock();
+            if !pipe.buffer.is_empty() {
+                let len = buf.len().min(pipe.buffer.len());
+                for (c, v) in buf.iter_mut().zip(pipe.buffer.drain(..len)) {
+                    *c = v;
+                }
+                drop(pipe);
+                process::wakeup(Pipe::write_channel(&self.0));
+                return Ok(len);
+            }
+
+            if pipe.writers == 0 {
+                // EOF
+                return Ok(0);
+            }
+
+            if flags & FLAGS_O_NONBLOCK != 0 {
+                return Err(EAGAIN);
+            }
+
+            // the lock is released once we are asleep
+            process::sleep_on(Pipe::read_channel(&self.0), pipe);
+
+            if process::signal_pending() {
+                return Err(EINTR);
+            }
+        }
+    }
+}
+
+impl FileOperation for PipeWriter {
+    fn write(&self, _: &mut usize, buf: &[u8], flags: usize) -> Result<usize, Errno> {
+        let mut written = 0;
+        while written < buf.len() {
+            let mut pipe = self.0.lock();
+            if pipe.readers == 0 {
+                // broken pipe
+                drop(pipe);
+                let _ = process::signal::send(process::current().pid, process::signal::SIGPIPE);
+                return Err(EPIPE);
+            }
+
+            let len = (buf.len() - written).min(PIPE_SIZE - pipe.buffer.len());
+            pipe.buffer.extend(&buf[written..written + len]);
+            written += len;
+
+            if len > 0 {
+                process::wakeup(Pipe::read_channel(&self.0));
+            }
+
+            if written < buf.len() {
+                // as much as there was room for
+                if flags & FLAGS_O_NONBLOCK != 0 {
+                    return match written {
+                        0 => Err(EAGAIN),
+                        _ => Ok(written),
+                    };
+                }
+
+                // wait for the readers
+                process::sleep_on(Pipe::write_channel(&self.0), pipe);
+
+                if process::signal_pending() {
+                    return match written {
+                        0 => Err(EINTR),
+                        _ => Ok(written),
+                    };
+                }
+            }
+        }
+        Ok(written)
+    }
+
+    fn read(&self, _: &mut usize, _: &mut [u8], _: usize) -> Result<usize, Errno> {
+        Err(EBADF)
+    }
+}
+
+impl Drop for PipeReader {
+    fn drop(&mut self) {
+        let mut pipe = self.0.lock();
+        pipe.readers -= 1;
+        // the writers will get a broken pipe
+        process::wakeup(Pipe::write_channel(&self.0));
+    }
+}
+
+impl Drop for PipeWriter {
+    fn drop(&mut self) {
+        let mut pipe = self.0.lock();
+        pipe.writers -= 1;
+        // the readers will get EOF
+        process::wakeup(Pipe::read_channel(&self.0));
+    }
+}
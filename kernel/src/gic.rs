@@ -7,6 +7,7 @@ static GICD_ICENABLER: u32 = 0x180;
 static GICD_IPRIORITY: u32 = 0x400;
 static GICD_ITARGETSR: u32 = 0x800;
 static GICD_ICFGR: u32 = 0xc00;
+static GICD_SGIR: u32 = 0xf00;
 
 static GICC_EOIR: u32 = 0x0010;
 static GICC_CTLR: u32 = 0x0000;
@@ -27,6 +28,20 @@ pub unsafe fn init() {
     GIC_CPU_IF.init();
 }
 
+// the cpu interface is banked, every cpu sets up its own
+pub unsafe fn init_cpu() {
+    GIC_CPU_IF.init();
+}
+
+pub fn ncpus() -> usize {
+    unsafe { GIC_DIST_IF.ncpus as usize }
+}
+
+// software generated interrupt
+pub fn send_sgi(cpu: usize, sgi: u32) {
+    unsafe { GIC_DIST_IF.send_sgi(cpu, sgi) };
+}
+
 pub fn irq_enable(irq_num: u32) {
     unsafe { GIC_DIST_IF.irq_enable(irq_num) };
 }
@@ -81,12 +96,6 @@ impl GicDistIf {
             self.write(ext_offset, val);
         }
 
-        // Enable CPU0's GIC interface
-        GIC_CPU_IF.write(GICC_CTLR, 1);
-
-        // Set CPU0's Interrupt Priority Mask
-        GIC_CPU_IF.write(GICC_PMR, 0xff);
-
         // Enable IRQ distribution
         self.write(GICD_CTLR, 0x1);
     }
@@ -99,6 +108,11 @@ impl GicDistIf {
         self.write(offset, 1 << (irq % 32));
     }
 
+    unsafe fn send_sgi(&mut self, cpu: usize, sgi: u32) {
+        // CPUTargetList [23:16], SGIINTID [3:0]
+        self.write(GICD_SGIR, (1 << (16 + cpu)) | (sgi & 0xf));
+    }
+
     // unsafe fn irq_disable(&mut self, irq: u32) {
     //     let offset = GICD_ICENABLER + (4 * (irq / 32));
     //     // let shift = 1 << (irq % 32);
@@ -122,6 +136,11 @@ pub struct GicCpuIf {
 
 impl GicCpuIf {
     unsafe fn init(&mut self) {
+        // Enable this CPU's GIC interface
+        self.write(GICC_CTLR, 1);
+
+        // Set this CPU's Interrupt Priority Mask
+        self.write(GICC_PMR, 0xff);
     }
 
     unsafe fn irq_eoi(&mut self, irq: u32) {
@@ -0,0 +1,141 @@
+use core::ops::{Deref, DerefMut};
+use core::mem::ManuallyDrop;
+use spin::{Mutex, MutexGuard};
+use crate::smp::{cpu_id, NCPU};
+
+// when the kernel takes interrupts:
+// - it's entered with them masked, from EL0 or by an exception in EL1.
+// - system calls and kernel threads run with them unmasked,
+//   the exception entries, the interrupt handlers, the scheduler
+//   and the way back to EL0 don't.
+// - an interrupt taken in EL1 never switches the process away,
+//   a time slice that ends there is noted and the process gives up
+//   the cpu on its way back to EL0, or in `cond_resched`.
+// - the locks the interrupt handlers take are `IrqMutex`,
+//   they keep the interrupts masked while they are held.
+//   a cpu interrupted with a plain `Mutex` held is fine as long as
+//   no handler takes it.
+
+// how many `push_off` haven't been popped yet on each cpu
+static mut DEPTH: [usize; NCPU] = [0; NCPU];
+// whether they were unmasked before the first `push_off`
+static mut WAS_ENABLED: [bool; NCPU] = [false; NCPU];
+
+pub fn enable() {
+    unsafe {
+        asm!("msr daifclr, #2");
+    }
+}
+
+pub fn disable() {
+    unsafe {
+        asm!("msr daifset, #2");
+    }
+}
+
+pub fn is_enabled() -> bool {
+    let daif: usize;
+    unsafe {
+        asm!("mrs {}, daif", out(reg) daif);
+    }
+    daif & (1 << 7) == 0
+}
+
+// like `disable`, but they nest
+pub fn push_off() {
+    let enabled = is_enabled();
+    disable();
+
+    let cpu = cpu_id();
+    unsafe {
+        if DEPTH[cpu] == 0 {
+            WAS_ENABLED[cpu] = enabled;
+        }
+        DEPTH[cpu] += 1;
+    }
+}
+
+pub fn pop_off() {
+    assert!(!is_enabled(), "pop_off: interrupts are unmasked");
+
+    let cpu = cpu_id();
+    unsafe {
+        assert!(DEPTH[cpu] > 0, "pop_off: unbalanced");
+        DEPTH[cpu] -= 1;
+        if DEPTH[cpu] == 0 && WAS_ENABLED[cpu] {
+            enable();
+        }
+    }
+}
+
+// the process is giving up the cpu with its last `push_off` pending.
+// the scheduler pops it and must stay masked, the process gets
+// the state back from `resume` wherever it runs next.
+pub fn suspend() -> bool {
+    unsafe {
+        core::mem::replace(&mut WAS_ENABLED[cpu_id()], false)
+    }
+}
+
+pub fn resume(enabled: bool) {
+    assert_eq!(unsafe { DEPTH[cpu_id()] }, 0, "resume: locks held across the switch");
+    if enabled {
+        enable();
+    }
+}
+
+// a spin lock that may be taken by the interrupt handlers
+pub struct IrqMutex<T: ?Sized> {
+    inner: Mutex<T>,
+}
+
+pub struct IrqMutexGuard<'a, T: ?Sized + 'a> {
+    guard: ManuallyDrop<MutexGuard<'a, T>>,
+}
+
+impl<T> IrqMutex<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            inner: Mutex::new(value),
+        }
+    }
+}
+
+impl<T: ?Sized> IrqMutex<T> {
+    pub fn lock(&self) -> IrqMutexGuard<T> {
+        push_off();
+        IrqMutexGuard {
+            guard: ManuallyDrop::new(self.inner.lock()),
+        }
+    }
+
+    // the guard has been forgotten by someone else
+    pub unsafe fn force_unlock(&self) {
+        self.inner.force_unlock();
+        pop_off();
+    }
+}
+
+impl<'a, T: ?Sized> Deref for IrqMutexGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<'a, T: ?Sized> DerefMut for IrqMutexGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+// unlocked before the interrupts may come back
+impl<'a, T: ?Sized> Drop for IrqMutexGuard<'a, T> {
+    fn drop(&mut self) {
+        unsafe {
+            ManuallyDrop::drop(&mut self.guard);
+        }
+        pop_off();
+    }
+}
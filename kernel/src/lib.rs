@@ -4,6 +4,7 @@
 #![feature(alloc_error_handler)]
 
 mod common;
+mod errno;
 mod fs;
 mod gic;
 #[macro_use]
@@ -12,7 +13,13 @@ mod syscall;
 mod mm;
 mod process;
 mod exception;
+mod fpsimd;
+mod irq;
 mod virtio;
+mod timer;
+mod uaccess;
+mod smp;
+mod workqueue;
 mod vm;
 
 extern crate alloc;
@@ -38,6 +45,7 @@ pub unsafe extern "C" fn start(
     fs::init();
 
     // enable timer
+    timer::init();
     gic::irq_enable(30);
 
     // uart init
@@ -46,6 +54,9 @@ pub unsafe extern "C" fn start(
     // enable uart irq
     gic::irq_enable(33);
 
+    // the other cpus wake us up when they make a process ready here
+    gic::irq_enable(smp::SGI_WAKEUP);
+
     // virtual memory initialization
     vm::init(kernel_tt, kernel_text_end);
 
@@ -55,13 +66,36 @@ pub unsafe extern "C" fn start(
     // init first process
     process::init_first(user_entry);
 
+    // kernel threads
+    workqueue::init();
+    process::spawn_kthread(fs::buffer::flusher);
+
+    // wake up the other cpus
+    smp::start_secondaries();
+
     // time to go
     process::schedule();
 }
 
+// the other cpus come here from `secondary_startup`
+#[no_mangle]
+pub unsafe extern "C" fn secondary_start() -> ! {
+    vm::init_cpu();
+
+    gic::init_cpu();
+
+    // the timer and SGIs are banked for every cpu
+    gic::irq_enable(30);
+    gic::irq_enable(smp::SGI_WAKEUP);
+
+    process::schedule();
+}
+
 #[panic_handler]
 pub fn panic(info: &core::panic::PanicInfo) -> ! {
-    println!("{}", info);
+    // not println!, this cpu may be holding PRINT_LOCK already
+    use core::fmt::Write;
+    let _ = writeln!(serial::SerialPort::new(), "{}", info);
     loop {}
 }
 
@@ -20,16 +20,18 @@ impl BuddyAllocator {
         Ok(list[order].remove())
     }
 
-    pub fn free(mut start: usize, end: usize) {
+    pub fn free(start: usize, end: usize) {
+        Self::free_into(&mut BUDDY_LIST.lock(), start, end);
+    }
+
+    fn free_into(list: &mut [FreeArea; 11], mut start: usize, end: usize) {
         while start < end {
             // determine the order, maximum is 10
             let mut order = (start >> PAGESHIFT).trailing_zeros() as usize;
             while order > 10 || start + (1 << (order + PAGESHIFT)) > end {
                 order -= 1;
             }
-            unsafe {
-                BUDDY_LIST[order].insert(start);
-            }
+            list[order].insert(start);
             start +=  1 << (PAGESHIFT + order);
         }
         assert_eq!(start, end);
@@ -66,11 +68,14 @@ unsafe impl FrameAlloc for BuddyAllocator {
         let order = pg_cnt.next_power_of_two().trailing_zeros() as usize;
 
         let res = {
-            Self::request(order, &mut BUDDY_LIST)
+            let mut list = BUDDY_LIST.lock();
+            Self::request(order, &mut list).map(|ptr| {
+                Self::free_into(&mut list, ptr + pg_cnt * PAGESIZE, ptr + (1 << (order + PAGESHIFT)));
+                ptr
+            })
         };
 
         if let Ok(ptr) = res {
-            Self::free(ptr + pg_cnt * PAGESIZE, ptr + (1 << (order + PAGESHIFT)));
             // initialized
             let ptr = ptr as *mut u8;
             core::slice::from_raw_parts_mut(ptr, pg_cnt * PAGESIZE).fill(0);
@@ -86,9 +91,10 @@ unsafe impl FrameAlloc for BuddyAllocator {
 
         assert!(addr & (PAGESIZE - 1) == 0);
 
-        BuddyAllocator::free(addr, addr + sz);
+        let mut list = BUDDY_LIST.lock();
+        BuddyAllocator::free_into(&mut list, addr, addr + sz);
 
-        BuddyAllocator::merge(&mut BUDDY_LIST);
+        BuddyAllocator::merge(&mut list);
 
     }
 }
@@ -1,4 +1,6 @@
-pub static mut BUDDY_LIST: [FreeArea; 11] = [FreeArea::new(); 11];
+use crate::irq::IrqMutex;
+
+pub static BUDDY_LIST: IrqMutex<[FreeArea; 11]> = IrqMutex::new([FreeArea::new(); 11]);
 
 #[repr(C)]
 #[derive(Clone, Copy)]
@@ -6,6 +8,9 @@ pub struct FreeArea {
     next: *mut FreeArea,
 }
 
+// the free pages are only reached through BUDDY_LIST
+unsafe impl Send for FreeArea {}
+
 impl FreeArea {
     pub fn next(&mut self) -> &mut *mut FreeArea {
         &mut self.next
@@ -0,0 +1,47 @@
+use crate::common::*;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Mutex;
+
+// reference count of every physical frame mapped into user space
+static FRAME_REF: Mutex<[u16; MEMSIZE >> PAGESHIFT]> = Mutex::new([0; MEMSIZE >> PAGESHIFT]);
+// the frames with a reference, changed with FRAME_REF held
+static IN_USE: AtomicUsize = AtomicUsize::new(0);
+
+fn index(pa: usize) -> usize {
+    assert!((KERNELBASE..PHYEND).contains(&pa));
+    (pa - KERNELBASE) >> PAGESHIFT
+}
+
+pub fn init_ref(pa: usize) {
+    let mut frame_ref = FRAME_REF.lock();
+    let cnt = &mut frame_ref[index(pa)];
+    if *cnt == 0 {
+        IN_USE.fetch_add(1, Ordering::Relaxed);
+    }
+    *cnt = 1;
+}
+
+pub fn get_ref(pa: usize) -> u16 {
+    FRAME_REF.lock()[index(pa)]
+}
+
+pub fn inc_ref(pa: usize) {
+    FRAME_REF.lock()[index(pa)] += 1;
+}
+
+// returns the remaining references
+pub fn dec_ref(pa: usize) -> u16 {
+    let mut frame_ref = FRAME_REF.lock();
+    let cnt = &mut frame_ref[index(pa)];
+    assert!(*cnt > 0, "frame 0x{:x} is not referenced", pa);
+    *cnt -= 1;
+    if *cnt == 0 {
+        IN_USE.fetch_sub(1, Ordering::Relaxed);
+    }
+    *cnt
+}
+
+// the frames of user space and the page cache
+pub fn in_use() -> usize {
+    IN_USE.load(Ordering::Relaxed)
+}
@@ -1,5 +1,6 @@
 pub mod buddyallocator;
 pub mod slaballocator;
+pub mod frame;
 mod buddylist;
 
 use crate::common::*;
@@ -1,6 +1,7 @@
 use alloc::alloc::{GlobalAlloc, Layout};
 use crate::common::*;
 use super::buddyallocator::BuddyAllocator;
+use crate::irq::IrqMutex;
 
 const SLAB_NODE_COUNT: usize = 8;
 const MINIMUM_SLAB_SIZE_SHIFT: usize = 3;
@@ -8,13 +9,16 @@ const MINIMUM_SLAB_SIZE: usize = 1 << MINIMUM_SLAB_SIZE_SHIFT;
 const MAXIMUM_SLAB_SIZE: usize = 1024;
 
 // 8, 16, 32, 64, 128, 256, 512, 1024
-static mut SLAB_LIST: [SlabNode; SLAB_NODE_COUNT]
-                            = [SlabNode(core::ptr::null_mut()); SLAB_NODE_COUNT];
+static SLAB_LIST: IrqMutex<[SlabNode; SLAB_NODE_COUNT]>
+                            = IrqMutex::new([SlabNode(core::ptr::null_mut()); SLAB_NODE_COUNT]);
 
 #[repr(transparent)]
 #[derive(Clone, Copy)]
 struct SlabNode(*mut SlabNode);
 
+// the free objects are only reached through SLAB_LIST
+unsafe impl Send for SlabNode {}
+
 impl SlabNode {
     unsafe fn alloc_one(&mut self, size: usize) -> *mut u8 {
         if self.0.is_null() {
@@ -63,7 +67,7 @@ unsafe impl GlobalAlloc for SlabAllocator {
 
         let (size, idx) = Self::get_size_and_index(layout.size());
 
-        SLAB_LIST[idx].alloc_one(size)
+        SLAB_LIST.lock()[idx].alloc_one(size)
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
@@ -74,7 +78,7 @@ unsafe impl GlobalAlloc for SlabAllocator {
 
         let (_, idx) = Self::get_size_and_index(layout.size());
 
-        SLAB_LIST[idx].dealloc_one(ptr);
+        SLAB_LIST.lock()[idx].dealloc_one(ptr);
 
     }
 }
@@ -1,5 +1,47 @@
+use alloc::vec::Vec;
+use crate::common::PAGESIZE;
+use crate::errno::Errno::{self, ENOEXEC};
+use crate::fs::{inode::Inode, pagecache};
+use super::space::AddressSpace;
+
+const ELFCLASS64:  u8  = 2;
+const ELFDATA2LSB: u8  = 1;
+const EV_CURRENT:  u8  = 1;
+const ET_EXEC:     u16 = 2;
+const ET_DYN:      u16 = 3;
+const EM_AARCH64:  u16 = 183;
+
+const PT_LOAD:     u32 = 1;
+const PT_DYNAMIC:  u32 = 2;
+const PT_INTERP:   u32 = 3;
+const PT_PHDR:     u32 = 6;
+
+const DT_NULL:     i64 = 0;
+const DT_NEEDED:   i64 = 1;
+const DT_RELA:     i64 = 7;
+const DT_RELASZ:   i64 = 8;
+const DT_RELAENT:  i64 = 9;
+const DT_REL:      i64 = 17;
+const DT_RELR:     i64 = 36;
+
+const R_AARCH64_NONE:     u32 = 0;
+const R_AARCH64_RELATIVE: u32 = 1027;
+
+// the auxiliary vector, what the program is told about itself
+pub const AT_NULL:   usize = 0;
+pub const AT_PHDR:   usize = 3;
+pub const AT_PHENT:  usize = 4;
+pub const AT_PHNUM:  usize = 5;
+pub const AT_PAGESZ: usize = 6;
+pub const AT_ENTRY:  usize = 9;
+pub const AT_RANDOM: usize = 25;
+
+pub const PF_X:    u32 = 1;
+pub const PF_W:    u32 = 2;
+pub const PF_R:    u32 = 4;
+
 #[repr(C)]
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct FileHeader {
     magic:         [u8; 4], // magic number
     class:         u8,  // 1 indicates 32bits, 2 for 64bits
@@ -12,7 +54,7 @@ pub struct FileHeader {
     machine:       u16,
     version_again: u32, // ???
     // memory address of the entry point from where the process start executing
-    entry:         u64,
+    pub(super) entry: u64,
     // points to the start of the program header table.
     // it usually follows the file header immediately
     phoff:         u64,
@@ -28,8 +70,15 @@ pub struct FileHeader {
     shstrndx:      u16,
 }
 
+impl FileHeader {
+    // position-independent, it's loaded wherever there's room
+    pub fn is_dyn(&self) -> bool {
+        self.ty == ET_DYN
+    }
+}
+
 #[repr(C)]
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug, Default)]
 pub struct ProgramHeader {
     pub(super) ty:     u32,
     pub(super) flags:  u32,
@@ -41,30 +90,177 @@ pub struct ProgramHeader {
     pub(super) align:  u64,
 }
 
+// an entry of the dynamic section
+#[repr(C)]
+#[derive(Debug, Default)]
+struct Dyn {
+    tag: i64,
+    val: u64,
+}
+
+// a relocation with an addend
+#[repr(C)]
+#[derive(Debug, Default)]
+pub struct Rela {
+    offset: u64, // where it goes, relative to the load base
+    info:   u64, // the type and the symbol
+    addend: i64,
+}
+
 impl ProgramHeader {
     pub fn is_loadable(&self) -> bool {
-        self.ty ==  0x01
+        self.ty == PT_LOAD && self.memsz != 0
+    }
+
+    // a segment is mapped from the file page by page,
+    // so it has to be at the same place in a page in both.
+    // where it goes in user space is up to the load base.
+    fn is_valid(&self, file_size: u64) -> bool {
+        let in_file = matches!(self.offset.checked_add(self.filesz), Some(end) if end <= file_size);
+        let align_ok = self.align <= 1 || self.align.is_power_of_two();
+
+        self.filesz <= self.memsz
+            && in_file
+            && align_ok
+            && self.offset % PAGESIZE as u64 == self.vaddr % PAGESIZE as u64
+            && self.vaddr.checked_add(self.memsz).is_some()
+    }
+
+    // where `len` bytes at `vaddr` are in the file, if they are all there
+    fn file_offset(&self, vaddr: u64, len: u64) -> Option<u64> {
+        match vaddr.checked_sub(self.vaddr)?.checked_add(len)? <= self.filesz {
+            true  => Some(self.offset + vaddr - self.vaddr),
+            false => None,
+        }
     }
 }
 
-pub fn read_fileheader(prog: &[u8]) -> &FileHeader {
-    let file_header = unsafe {
-        &*(prog.as_ptr() as *const FileHeader)
+// the bytes at `pos` of the file as a `T`, which is plain old data
+fn read_struct<T: Default>(inode: &Inode, pos: u64) -> Result<T, Errno> {
+    let mut value = T::default();
+    let buf = unsafe {
+        core::slice::from_raw_parts_mut(&mut value as *mut T as *mut u8, core::mem::size_of::<T>())
     };
 
-    assert_eq!(file_header.magic, [0x7f, 0x45, 0x4c, 0x46]);
-    file_header
+    match pagecache::read(inode, pos as usize, buf)? {
+        len if len == buf.len() => Ok(value),
+        _ => Err(ENOEXEC),
+    }
 }
 
-pub fn read_program_header_table<'a>(
-    prog: &'a [u8],
-    file_header: &FileHeader
-) -> &'a [ProgramHeader] {
-    let ptr = unsafe {
-        prog.as_ptr().add(file_header.phoff as usize) as *const ProgramHeader
+pub fn read_fileheader(inode: &Inode) -> Result<FileHeader, Errno> {
+    let file_header = read_struct::<FileHeader>(inode, 0)?;
+
+    let valid = file_header.magic == [0x7f, 0x45, 0x4c, 0x46]
+        && file_header.class == ELFCLASS64
+        && file_header.data == ELFDATA2LSB
+        && file_header.version == EV_CURRENT
+        && (file_header.ty == ET_EXEC || file_header.ty == ET_DYN)
+        && file_header.machine == EM_AARCH64
+        && file_header.phentsize as usize == core::mem::size_of::<ProgramHeader>()
+        && file_header.phnum != 0;
+
+    match valid {
+        true  => Ok(file_header),
+        false => Err(ENOEXEC),
+    }
+}
+
+// every loadable segment is checked against the file
+pub fn read_program_header_table(inode: &Inode, file_header: &FileHeader) -> Result<Vec<ProgramHeader>, Errno> {
+    let file_size = inode.size() as u64;
+    let table_size = file_header.phnum as u64 * file_header.phentsize as u64;
+    if !matches!(file_header.phoff.checked_add(table_size), Some(end) if end <= file_size) {
+        return Err(ENOEXEC);
+    }
+
+    let mut table = Vec::with_capacity(file_header.phnum as usize);
+    for i in 0..file_header.phnum as u64 {
+        let header = read_struct::<ProgramHeader>(inode, file_header.phoff + i * file_header.phentsize as u64)?;
+        // there is no dynamic linker
+        if header.ty == PT_INTERP || header.is_loadable() && !header.is_valid(file_size) {
+            return Err(ENOEXEC);
+        }
+        table.push(header);
+    }
+    Ok(table)
+}
+
+// where the program header table is in memory, before relocation.
+// it's given by PT_PHDR, or else by the segment it's loaded with.
+pub fn phdr_vaddr(file_header: &FileHeader, table: &[ProgramHeader]) -> Option<u64> {
+    if let Some(phdr) = table.iter().find(|header| header.ty == PT_PHDR) {
+        return Some(phdr.vaddr);
+    }
+
+    let size = file_header.phnum as u64 * file_header.phentsize as u64;
+    table.iter()
+         .filter(|header| header.is_loadable())
+         .find(|header| header.offset <= file_header.phoff && file_header.phoff + size <= header.offset + header.filesz)
+         .map(|header| header.vaddr + file_header.phoff - header.offset)
+}
+
+// the relocations of a position-independent program, found through its dynamic section.
+// they can only be relative, there's nothing to look up symbols in.
+pub fn read_relocations(inode: &Inode, table: &[ProgramHeader]) -> Result<Vec<Rela>, Errno> {
+    let dynamic = match table.iter().find(|header| header.ty == PT_DYNAMIC) {
+        Some(dynamic) => dynamic,
+        None => return Ok(Vec::new()),
     };
+    if !matches!(dynamic.offset.checked_add(dynamic.filesz), Some(end) if end <= inode.size() as u64) {
+        return Err(ENOEXEC);
+    }
+
+    let (mut rela, mut relasz, mut relaent) = (0, 0, core::mem::size_of::<Rela>() as u64);
+    let dyn_size = core::mem::size_of::<Dyn>() as u64;
+    for i in 0..dynamic.filesz / dyn_size {
+        let entry = read_struct::<Dyn>(inode, dynamic.offset + i * dyn_size)?;
+        match entry.tag {
+            DT_NULL    => break,
+            DT_RELA    => rela = entry.val,
+            DT_RELASZ  => relasz = entry.val,
+            DT_RELAENT => relaent = entry.val,
+            DT_NEEDED | DT_REL | DT_RELR => return Err(ENOEXEC),
+            _ => {}
+        }
+    }
+    if relasz == 0 {
+        return Ok(Vec::new());
+    }
+    if relaent != core::mem::size_of::<Rela>() as u64 {
+        return Err(ENOEXEC);
+    }
+
+    // they are in a loadable segment
+    let pos = table.iter()
+                   .filter(|header| header.is_loadable())
+                   .find_map(|header| header.file_offset(rela, relasz))
+                   .ok_or(ENOEXEC)?;
 
-    unsafe {
-        core::slice::from_raw_parts(ptr, file_header.phnum as usize)
+    let mut relocations = Vec::new();
+    for i in 0..relasz / relaent {
+        let rela = read_struct::<Rela>(inode, pos + i * relaent)?;
+        match rela.info as u32 {
+            R_AARCH64_NONE => {}
+            R_AARCH64_RELATIVE => relocations.push(rela),
+            _ => return Err(ENOEXEC),
+        }
     }
-}
\ No newline at end of file
+    Ok(relocations)
+}
+
+// the program is moved to `base`, the pages written to are its own from now on
+pub fn relocate(space: &mut AddressSpace, base: usize, relocations: &[Rela]) -> Result<(), Errno> {
+    for rela in relocations {
+        let va = base.wrapping_add(rela.offset as usize);
+        if va % core::mem::size_of::<usize>() != 0 || space.vmas.find(va).is_none() {
+            return Err(ENOEXEC);
+        }
+
+        let page = space.private_page(va)?;
+        unsafe {
+            *((page + va % PAGESIZE) as *mut usize) = base.wrapping_add(rela.addend as usize);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,128 @@
+use super::*;
+use crate::errno::Errno::{self, EAGAIN, EFAULT, EINTR, EINVAL, ETIMEDOUT};
+
+pub const FUTEX_WAIT:         usize = 0;
+pub const FUTEX_WAKE:         usize = 1;
+pub const FUTEX_REQUEUE:      usize = 3;
+// every futex is looked up by its physical address anyway
+pub const FUTEX_PRIVATE_FLAG: usize = 128;
+
+// the waiters sleep on the physical address of the futex word,
+// so it's the same channel in every address space the page is mapped into.
+// it's held between checking the word and going to sleep, so a wake in between isn't lost.
+// nothing under it may fault or sleep, the word is read through its physical address.
+static FUTEX: Mutex<()> = Mutex::new(());
+
+// the word is read first, it faults the page in if it isn't there.
+// it may sleep on the disk, so it's never called with `FUTEX` held.
+fn channel(uaddr: *const u32) -> Result<usize, Errno> {
+    if uaddr.is_null() || uaddr as usize % 4 != 0 {
+        return Err(EINVAL);
+    }
+
+    uaccess::read_user(uaddr)?;
+
+    let va = uaddr as usize;
+    let mut space = current().space();
+    let page_tb = space.page_tb();
+    if !page_tb.is_accessible(va, true) {
+        // a page shared since fork would get a new frame on the first write,
+        // copy it now, so the address of the word stays the same while anyone sleeps on it.
+        // a read-only page is fine to wait on.
+        let _ = page_tb.copy_on_write(round_down(va));
+    }
+    page_tb.translate(va).ok_or(EFAULT)
+}
+
+// `FUTEX` and the channels of the words.
+// a page may be unmapped or replaced before the lock is taken, then it's looked up again.
+fn lock<const N: usize>(uaddrs: [*const u32; N]) -> Result<(MutexGuard<'static, ()>, [usize; N]), Errno> {
+    loop {
+        let mut channels = [0; N];
+        for (channel, &uaddr) in channels.iter_mut().zip(uaddrs.iter()) {
+            *channel = self::channel(uaddr)?;
+        }
+
+        let guard = FUTEX.lock();
+        let mut space = current().space();
+        let page_tb = space.page_tb();
+        if uaddrs.iter().zip(channels.iter()).all(|(&uaddr, &channel)| page_tb.translate(uaddr as usize) == Some(channel)) {
+            drop(space);
+            return Ok((guard, channels));
+        }
+    }
+}
+
+// sleep while the word at `uaddr` is `val`, `timeout` is relative
+pub fn wait(uaddr: *const u32, val: u32, timeout: *const TimeSpec) -> Result<usize, Errno> {
+    let deadline = match timeout.is_null() {
+        true => None,
+        false => {
+            let ticks = uaccess::read_user(timeout)?.to_ticks().ok_or(EINVAL)?;
+            Some(timer::now().saturating_add(ticks))
+        }
+    };
+
+    let (guard, [channel]) = lock([uaddr])?;
+    // the kernel maps all of the physical memory
+    if unsafe { core::ptr::read_volatile(channel as *const u32) } != val {
+        // it has changed, try again
+        return Err(EAGAIN);
+    }
+
+    let timed_out = match deadline {
+        Some(deadline) => sleep_timeout_on(channel, deadline, guard),
+        None => {
+            sleep_on(channel, guard);
+            false
+        }
+    };
+
+    // spurious wakeups are up to user space
+    if timed_out {
+        return Err(ETIMEDOUT);
+    }
+    if signal_pending() {
+        return Err(EINTR);
+    }
+    Ok(0)
+}
+
+// returns how many were woken up
+pub fn wake(uaddr: *const u32, count: usize) -> Result<usize, Errno> {
+    let (_guard, [channel]) = lock([uaddr])?;
+    Ok(wake_locked(&mut **scheduler(), channel, count))
+}
+
+// wake up `count` waiters and move at most `requeue` of the others to `uaddr2`,
+// returns how many were woken up
+pub fn requeue(uaddr: *const u32, count: usize, uaddr2: *const u32, requeue: usize) -> Result<usize, Errno> {
+    let (_guard, [from, to]) = lock([uaddr, uaddr2])?;
+
+    let mut sched = scheduler();
+    let woken = wake_locked(&mut **sched, from, count);
+
+    let list = process_list();
+    let waiters = list.values()
+                        .map(|ptr| unsafe { &mut **ptr })
+                        .filter(|proc| proc.is_waiting_on(from))
+                        .take(requeue);
+    for proc in waiters {
+        proc.channel = Some(to);
+    }
+    Ok(woken)
+}
+
+fn wake_locked(sched: &mut dyn Scheduler, channel: usize, count: usize) -> usize {
+    let list = process_list();
+    let waiters = list.values()
+                        .map(|ptr| unsafe { &mut **ptr })
+                        .filter(|proc| proc.is_waiting_on(channel))
+                        .take(count);
+    let mut woken = 0;
+    for proc in waiters {
+        proc.make_ready(sched, false);
+        woken += 1;
+    }
+    woken
+}
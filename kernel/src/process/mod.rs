@@ -1,22 +1,55 @@
 use crate::vm::*;
 use alloc::vec;
 use alloc::boxed::Box;
+use alloc::sync::Arc;
 use crate::common::*;
 use crate::exception::UserContext;
 use crate::fs::file::*;
 use core::mem::MaybeUninit;
 use alloc::collections::vec_deque::VecDeque;
+use alloc::collections::btree_map::BTreeMap;
 use alloc::vec::Vec;
 use crate::fs::{self, inode::Inode};
+use crate::timer::{self, TimeSpec, TimerHandle};
+use crate::smp::{self, cpu_id, NCPU};
+use crate::fpsimd::{self, FpState};
+use crate::irq::{self, IrqMutex, IrqMutexGuard};
+use crate::uaccess;
+use crate::errno::Errno::{self, E2BIG, EACCES, EBADF, ECHILD, EINTR, EINVAL, ELOOP, EMFILE, ENODEV, ENOENT, ENOEXEC, ENOMEM, EPERM, ERANGE, ESRCH};
+use spin::{Mutex, MutexGuard};
 
 mod elf;
-
-static mut PROCESS_LIST: [*mut Process; 20] = [core::ptr::null_mut(); 20];
-static mut SCHEDULER_CONTEXT: Context = Context::new();
-static mut USER_INPUT: MaybeUninit<(VecDeque<u8>, Vec<*mut Process>)> = MaybeUninit::uninit();
+mod script;
+mod space;
+pub mod vma;
+pub mod futex;
+pub mod signal;
+pub mod scheduler;
+
+use signal::SignalState;
+use scheduler::{Scheduler, Mlfq};
+use space::AddressSpace;
+use vma::{Vma, VmaFile, VmaList, PROT_READ, PROT_WRITE, PROT_EXEC};
+
+static mut PROCESS_LIST: MaybeUninit<IrqMutex<BTreeMap<u32, *mut Process>>> = MaybeUninit::uninit();
+static NEXT_PID: Mutex<u32> = Mutex::new(1);
+// only touched by its own cpu
+static mut SCHEDULER_CONTEXT: [Context; NCPU] = [Context::IDLE; NCPU];
+// besides the run queues, it protects the state, the channel, the signals
+// and the parent and children of every process.
+// a process gives up the cpu with it held, the scheduler releases it after the switch.
+static mut SCHEDULER: MaybeUninit<IrqMutex<Box<dyn Scheduler>>> = MaybeUninit::uninit();
+// the time slice ended while the cpu was in EL1
+static mut NEED_RESCHED: [bool; NCPU] = [false; NCPU];
+static mut USER_INPUT: MaybeUninit<Mutex<(VecDeque<u8>, Vec<u32>)>> = MaybeUninit::uninit();
+
+const PID_MAX: u32 = 1 << 22;
+// in nanoseconds
+const TIME_SLICE_UNIT: u64 = 4_000_000;
 
 extern "C" {
     fn switch(from: *mut Context, to: *const Context);
+    fn kthread_start();
 }
 
 #[repr(C)]
@@ -34,11 +67,14 @@ struct Context {
     x26    : usize,  // 80
     x27    : usize,  // 88
     x28    : usize,  // 96
-    x29    : usize,  // 108
-    x30    : usize,  // 116
+    x29    : usize,  // 104
+    x30    : usize,  // 112
+    tpidr_el0 : usize, // 120
 }
 
 impl Context {
+    const IDLE: Context = Context::new();
+
     const fn new() -> Self {
         Self {
             sp_el0 : 0,
@@ -56,6 +92,7 @@ impl Context {
             x28    : 0,
             x29    : 0,
             x30    : 0, // link register
+            tpidr_el0 : 0, // thread pointer
         }
     }
 }
@@ -65,103 +102,250 @@ enum ProcessState {
     Blocking,
     Ready,
     Running,
+    Stopped,
     Dead,
 }
 
+// every thread is a `Process`, the threads of a process share
+// the address space, the open files and the cwd
 pub struct Process {
-    pub pid: u8,
+    pub pid: u32,
+    // thread group id, the pid of the first thread
+    pub tgid: u32,
     state: ProcessState,
     context: Context,
-    stack_size: usize,
-    heap_start: usize,
-    heap_end: usize,
     sp_el1: Box<[u8]>,
-    page_tb: PageTable,
+    // kernel threads have no user space
+    mm: Option<Arc<Mutex<AddressSpace>>>,
     channel: Option<usize>,
-    child: Vec<u8>,
-    cwd: Option<u32>,
+    parent: u32,
+    child: Vec<u32>,
+    // wait status, valid after the process is dead
+    exit_status: usize,
+    // set on the first thread by whoever ends the whole thread group
+    group_exit: Option<usize>,
+    // zeroed when the thread exits, so others can join it
+    clear_child_tid: usize,
+    cwd: Arc<Mutex<Option<u32>>>,
     // 0 => stdin
     // 1 => stdout
-    file: [Option<File>; 10],
+    file: Arc<Mutex<Vec<Option<FileDesc>>>>,
+    signal: SignalState,
+    alarm: Option<TimerHandle>,
+    // none until it's first used
+    fpsimd: Option<Box<FpState>>,
 }
 
 impl Process {
-    const USER_BASE_ADDR: usize = 0xffff_0000_0000_0000;
-    const USER_STACK_TOP: usize = 0xffff_ffff_ffff_0000;
+    pub const USER_BASE_ADDR: usize = 0xffff_0000_0000_0000;
+    pub const USER_STACK_TOP: usize = 0xffff_ffff_ffff_0000;
     const USER_HEAP_SIZE_LIMIT: usize = 10 * PAGESIZE;
+    const FILE_DESC_LIMIT: usize = 256;
 
     fn is_ready(&self) -> bool {
         self.state == ProcessState::Ready
     }
 
-    fn default_file_dec() -> [Option<File>; 10] {
-        let mut file: [Option<File>; 10] = Default::default();
-        file[0] = Some(File::stdio());
-        file[1] = Some(File::stdio());
-        file
+    pub fn is_kernel_thread(&self) -> bool {
+        self.mm.is_none()
+    }
+
+    // the first thread, it's the one known to the parent
+    fn is_leader(&self) -> bool {
+        self.pid == self.tgid
+    }
+
+    fn default_file_dec() -> Vec<Option<FileDesc>> {
+        vec![
+            Some(FileDesc::new(File::stdio(), false)),
+            Some(FileDesc::new(File::stdio(), false)),
+        ]
     }
 
     pub unsafe fn get_cwd(&mut self) -> &mut Inode {
-        fs::get_inode(self.cwd.unwrap())
+        fs::get_inode(self.cwd.lock().unwrap())
     }
 
     pub fn chdir(&mut self, dir: u32) {
-        self.cwd = Some(dir);
+        *self.cwd.lock() = Some(dir);
+    }
+
+    pub fn get_file_desc(&self, fd: usize) -> Result<Arc<File>, Errno> {
+        Ok(self.file_desc(fd)?.file)
+    }
+
+    pub fn file_desc(&self, fd: usize) -> Result<FileDesc, Errno> {
+        self.file.lock().get(fd).cloned().flatten().ok_or(EBADF)
+    }
+
+    pub fn insert_file_desc(&mut self, file: Arc<File>, cloexec: bool) -> Result<usize, Errno> {
+        self.insert_file_desc_from(0, FileDesc::new(file, cloexec))
     }
 
-    pub fn get_file_desc_mut(&mut self, fd: usize) -> Result<&mut File, isize> {
-        if fd >= 5 {
-            return Err(-1);
+    // install `desc` at the lowest free descriptor which is not less than `from`
+    pub fn insert_file_desc_from(&mut self, from: usize, desc: FileDesc) -> Result<usize, Errno> {
+        if from >= Process::FILE_DESC_LIMIT {
+            return Err(EINVAL);
         }
-        self.file[fd].as_mut().ok_or(-1)
+
+        let mut file = self.file.lock();
+        let fd = file.iter()
+                        .enumerate()
+                        .skip(from)
+                        .find(|(_, desc)| desc.is_none())
+                        .map(|(fd, _)| fd)
+                        .unwrap_or_else(|| file.len().max(from));
+        if fd >= Process::FILE_DESC_LIMIT {
+            return Err(EMFILE);
+        }
+
+        Process::install(&mut file, fd, desc)?;
+        Ok(fd)
+    }
+
+    // install `desc` at `fd`, the file previously opened there is closed
+    pub fn install_file_desc(&mut self, fd: usize, desc: FileDesc) -> Result<(), Errno> {
+        Process::install(&mut self.file.lock(), fd, desc)
+    }
+
+    fn install(file: &mut Vec<Option<FileDesc>>, fd: usize, desc: FileDesc) -> Result<(), Errno> {
+        if fd >= Process::FILE_DESC_LIMIT {
+            return Err(EBADF);
+        }
+
+        if fd >= file.len() {
+            file.resize(fd + 1, None);
+        }
+        file[fd] = Some(desc);
+        Ok(())
+    }
+
+    pub fn remove_file_desc(&mut self, fd: usize) -> Result<Arc<File>, Errno> {
+        let desc = self.file.lock().get_mut(fd).ok_or(EBADF)?.take().ok_or(EBADF)?;
+        Ok(desc.file)
     }
 
-    pub fn insert_file_desc(&mut self, file: File) -> Result<usize, isize> {
-        let res = self.file.iter_mut()
-                            .enumerate()
-                            .find(|(_, desc)| desc.is_none()).ok_or(-1_isize)?;
-        *res.1 = Some(file);
-        Ok(res.0)
+    pub fn set_cloexec(&mut self, fd: usize, cloexec: bool) -> Result<(), Errno> {
+        self.file.lock()
+                    .get_mut(fd)
+                    .and_then(|desc| desc.as_mut())
+                    .ok_or(EBADF)?
+                    .cloexec = cloexec;
+        Ok(())
+    }
+
+    fn close_on_exec(&mut self) {
+        for desc in self.file.lock().iter_mut() {
+            if matches!(desc, Some(d) if d.cloexec) {
+                *desc = None;
+            }
+        }
+    }
+
+    // channel that the parent sleeps on while waiting for its children
+    fn child_channel(&self) -> usize {
+        self as *const Process as usize
+    }
+
+    // channel that the first thread sleeps on while waiting for the others to exit
+    fn thread_channel(&self) -> usize {
+        core::ptr::addr_of!(self.tgid) as usize
     }
 
     fn is_waiting_on(&self, channel: usize) -> bool {
        matches!(self.channel, Some(ch) if ch == channel)
     }
 
-    pub fn wakeup(&mut self) {
+    // hand it to the scheduler, `interactive` means it was waiting for user input
+    fn make_ready(&mut self, sched: &mut dyn Scheduler, interactive: bool) {
+        if matches!(self.state, ProcessState::Ready | ProcessState::Running | ProcessState::Dead) {
+            return;
+        }
+
         self.channel = None;
         self.state = ProcessState::Ready;
+        let cpu = sched.wake(self.pid, interactive);
+        smp::wakeup(cpu);
     }
 
-    pub fn heap_end(&self) -> usize {
-        self.heap_end
+    pub fn space(&self) -> MutexGuard<AddressSpace> {
+        self.mm.as_ref().expect("kernel thread has no user space").lock()
     }
 
-    pub fn page_tb(&mut self) -> &mut PageTable {
-        &mut self.page_tb
+    // only the running process can have its registers live
+    fn save_fpsimd(&mut self) {
+        if fpsimd::is_live() {
+            if let Some(state) = &mut self.fpsimd {
+                state.save();
+            }
+        }
+    }
+
+    fn ttbr1(&mut self) -> usize {
+        match &self.mm {
+            Some(mm) => mm.lock().ttbr1(),
+            None => kernel_table(),
+        }
+    }
+
+    // the context of user process is placed on the bottom of kernel stack
+    pub fn user_context(&mut self) -> &'static mut UserContext {
+        unsafe {
+            &mut *(self.sp_el1.as_mut_ptr() as *mut UserContext)
+        }
+    }
+}
+
+// take it before the process list, never after
+fn scheduler() -> IrqMutexGuard<'static, Box<dyn Scheduler>> {
+    unsafe {
+        SCHEDULER.assume_init_ref().lock()
+    }
+}
+
+// the processes are freed only by `reap`, so the references outlive the lock
+fn process_list() -> IrqMutexGuard<'static, BTreeMap<u32, *mut Process>> {
+    unsafe {
+        PROCESS_LIST.assume_init_ref().lock()
+    }
+}
+
+fn find(pid: u32) -> Option<&'static mut Process> {
+    process_list().get(&pid).map(|ptr| unsafe { &mut **ptr })
+}
+
+// a sleeping process should give up when this is true
+pub fn signal_pending() -> bool {
+    current().signal.has_pending()
+}
+
+fn user_input() -> MutexGuard<'static, (VecDeque<u8>, Vec<u32>)> {
+    unsafe {
+        USER_INPUT.assume_init_ref().lock()
     }
 }
 
 pub fn put_user_input(c: u8) {
-    let (buffer, waiting_list) = unsafe {
-        USER_INPUT.assume_init_mut()
-    };
+    let mut input = user_input();
+    let (buffer, waiting_list) = &mut *input;
 
     buffer.push_back(c);
-    for proc in waiting_list {
-        unsafe {
-            (**proc).wakeup();
+    let mut sched = scheduler();
+    for pid in waiting_list.drain(..) {
+        if let Some(proc) = find(pid) {
+            if proc.state == ProcessState::Blocking {
+                proc.make_ready(&mut **sched, true);
+            }
         }
     }
 }
 
-pub fn get_user_input(buf: &mut [u8]) -> Result<usize, isize> {
-    let (buffer, waiting_list) = unsafe {
-        USER_INPUT.assume_init_mut()
-    };
+pub fn get_user_input(buf: &mut [u8]) -> Result<usize, Errno> {
+    let mut input = user_input();
 
     let mut len = 0;
     loop {
+        let (buffer, waiting_list) = &mut *input;
         while let Some(c) = buffer.pop_front() {
             if len > 0 || (c != 8 && c != 0x7f) {
                 print!("{}", c as char);
@@ -174,30 +358,38 @@ pub fn get_user_input(buf: &mut [u8]) -> Result<usize, isize> {
                     // backspace
                     if len > 0 {
                         len -= 1;
-                        *(buf.get_mut(len).ok_or(-1_isize)?) = 0;
+                        *(buf.get_mut(len).ok_or(EINVAL)?) = 0;
                     }
                 }
                 _ => {
-                    *(buf.get_mut(len).ok_or(-1_isize)?) = c;
+                    *(buf.get_mut(len).ok_or(EINVAL)?) = c;
                     len += 1;
                 }
             }
         }
         let proc = current();
-        waiting_list.push(proc as *mut Process);
-        sleep(0);
+        waiting_list.push(proc.pid);
+        sleep_on(0, input);
+
+        if signal_pending() {
+            return Err(EINTR);
+        }
+        input = user_input();
     }
 }
 
-fn alloc_process() -> u8 {
-    unsafe {
-        for (i, proc) in PROCESS_LIST.iter_mut().enumerate() {
-            if proc.is_null() {
-                return i as u8;
-            }
+// pids are handed out in increasing order, so a pid is not reused right away
+// the pid is taken once the process is inserted into `list`
+fn alloc_pid(list: &BTreeMap<u32, *mut Process>) -> u32 {
+    let mut next_pid = NEXT_PID.lock();
+    loop {
+        let pid = *next_pid;
+        *next_pid = if pid + 1 < PID_MAX { pid + 1 } else { 1 };
+
+        if !list.contains_key(&pid) {
+            return pid;
         }
     }
-    panic!("Running out of process");
 }
 
 pub fn init_first(user_entry: usize) {
@@ -229,153 +421,301 @@ pub fn init_first(user_entry: usize) {
     let mut context = Context::new();
     context.sp_el0 = Process::USER_STACK_TOP;
     context.sp_el1 = sp_el1.as_ptr() as usize + 4 * PAGESIZE;
-    context.ttbr1  = page_tb.as_ptr() as usize;
     context.x30 = crate::exception::back_to_earth as *const fn() as usize;
 
+    let mut vmas = VmaList::new();
+    vmas.insert(Vma::new(Process::USER_BASE_ADDR, Process::USER_BASE_ADDR + PAGESIZE, PROT_READ | PROT_EXEC));
+    vmas.insert(Vma::new(Process::USER_STACK_TOP - PAGESIZE, Process::USER_STACK_TOP, PROT_READ | PROT_WRITE));
+    let space = AddressSpace::new(page_tb, vmas, PAGESIZE, Process::USER_BASE_ADDR + PAGESIZE);
+
     let proc = Process {
         pid: 0,
+        tgid: 0,
         state: ProcessState::Ready,
 
         context,
-        stack_size: PAGESIZE,
-        heap_start: Process::USER_BASE_ADDR + PAGESIZE,
-        heap_end: Process::USER_BASE_ADDR + PAGESIZE,
         sp_el1,
-        page_tb,
+        mm: Some(Arc::new(Mutex::new(space))),
+        parent: 0,
         child: Vec::new(),
+        exit_status: 0,
+        group_exit: None,
+        clear_child_tid: 0,
         channel: None,
-        cwd: None,
-        file: Process::default_file_dec(),
+        cwd: Arc::new(Mutex::new(None)),
+        file: Arc::new(Mutex::new(Process::default_file_dec())),
+        signal: SignalState::new(),
+        alarm: None,
+        fpsimd: None,
     };
 
     unsafe {
-        PROCESS_LIST[0] = Box::into_raw(Box::new(proc));
-        USER_INPUT = MaybeUninit::new((VecDeque::new(), Vec::new()));
+        PROCESS_LIST = MaybeUninit::new(IrqMutex::new(BTreeMap::new()));
+        process_list().insert(0, Box::into_raw(Box::new(proc)));
+        SCHEDULER = MaybeUninit::new(IrqMutex::new(Box::new(Mlfq::new(smp::ncpu()))));
+        let mut sched = scheduler();
+        sched.add(0, None);
+        sched.wake(0, false);
+        USER_INPUT = MaybeUninit::new(Mutex::new((VecDeque::new(), Vec::new())));
     }
 }
 
-pub fn exec(path: &[u8], argv: Vec<Vec<u8>>) -> Result<usize, isize> {
-    let mut inode = crate::fs::open(path, crate::fs::FLAGS_O_RDONLY)?;
-    let mut program = Vec::new();
-    program.resize(inode.size() as usize, 0);
-    inode.read(&mut 0, &mut program)?;
+pub fn exec(path: &[u8], mut argv: Vec<Vec<u8>>, envp: Vec<Vec<u8>>) -> Result<usize, Errno> {
+    // a script is run by its interpreter, with the path of the script
+    // in place of argv[0] and the argument from the `#!` line before it
+    let mut path = path.to_vec();
+    let mut depth = 0;
+    let inode = loop {
+        let inode = crate::fs::open(&path, crate::fs::FLAGS_O_RDONLY)?;
+        let (interpreter, arg) = match script::read_interpreter(inode)? {
+            Some(interpreter) => interpreter,
+            None => break inode,
+        };
+
+        depth += 1;
+        if depth > script::MAX_DEPTH {
+            return Err(ELOOP);
+        }
 
-    let file_header = elf::read_fileheader(&program);
+        let with_nul = |s: &[u8]| s.iter().copied().chain(core::iter::once(0)).collect::<Vec<_>>();
+        let mut args = vec![with_nul(&interpreter)];
+        args.extend(arg.as_deref().map(with_nul));
+        args.push(with_nul(&path));
+        args.extend(argv.drain(..).skip(1));
+        if args.iter().chain(envp.iter()).map(|s| s.len() + core::mem::size_of::<usize>()).sum::<usize>() >= uaccess::ARG_MAX {
+            return Err(E2BIG);
+        }
 
-    let prog_header_table = elf::read_program_header_table(&program, file_header);
+        argv = args;
+        path = interpreter;
+    };
 
-    let proc = current();
+    let file_header = elf::read_fileheader(inode)?;
 
-    if proc.cwd.is_none() {
-        proc.cwd = Some(inode.parent)
+    let prog_header_table = elf::read_program_header_table(inode, &file_header)?;
+
+    let relocations = elf::read_relocations(inode, &prog_header_table)?;
+
+    let proc = current();
+    // the other threads could only be killed, and the first one with them
+    if !proc.is_leader() {
+        return Err(EPERM);
     }
 
-    let mut page_tb = PageTable::new();
+    // argv and envp go on top of the stack, the program gets at least a page below it
+    let stack_size = round_up(stack_args_size(&argv, &envp)) + PAGESIZE;
+    let stack = Process::USER_STACK_TOP - stack_size;
+
+    let mut vmas = VmaList::new();
+    vmas.insert(Vma::new(stack, Process::USER_STACK_TOP, PROT_READ | PROT_WRITE));
+
+    // the pages the file ends in with .bss right after it,
+    // they are filled now as the rest of them has to be zeroed
+    let mut partial = Vec::new();
+    let mut heap_start = Process::USER_BASE_ADDR;
 
-    let mut curr = Process::USER_BASE_ADDR;
-
-    for header in prog_header_table {
-        if header.is_loadable() {
-            let perm = match header.flags {
-                0b111 => "rwx",
-                0b110 => "rw",
-                0b100 => "r",
-                _ => unimplemented!()
-            };
-
-            let size = header.filesz as usize;
-            let src = unsafe {
-                program.as_ptr().add(header.offset as usize)
-            };
-
-            let dst =  page_tb.create(curr, round_up(size), perm)?;
-            
-            unsafe {
-                core::ptr::copy_nonoverlapping(src, dst as *mut u8, size);
+    // a position-independent program goes to the bottom of user space
+    let base = match file_header.is_dyn() {
+        true  => Process::USER_BASE_ADDR,
+        false => 0,
+    };
+
+    for header in prog_header_table.iter().filter(|header| header.is_loadable()) {
+        let prot = [(elf::PF_R, PROT_READ), (elf::PF_W, PROT_WRITE), (elf::PF_X, PROT_EXEC)]
+            .iter()
+            .filter(|(flag, _)| header.flags & flag != 0)
+            .fold(0, |prot, (_, bit)| prot | bit);
+
+        let vaddr = base.wrapping_add(header.vaddr as usize);
+        if !uaccess::access_ok(vaddr, header.memsz as usize) {
+            return Err(ENOEXEC);
+        }
+
+        let start = round_down(vaddr);
+        let file_end = vaddr + header.filesz as usize;
+        let end = round_up(vaddr + header.memsz as usize);
+        if !vmas.is_free(start, end) {
+            return Err(ENOEXEC);
+        }
+
+        // the pages of the file are read in when they are touched
+        let file_offset = round_down(header.offset as usize);
+        let anon_start = match file_end % PAGESIZE != 0 && header.memsz > header.filesz {
+            true => {
+                let va = round_down(file_end);
+                partial.push((va, file_offset + va - start, file_end - va, prot));
+                va
             }
+            false => round_up(file_end),
+        };
 
-            curr += round_up(size);
+        if start < anon_start {
+            let file = VmaFile { inode: inode.num, offset: file_offset, shared: false };
+            vmas.insert(Vma { file: Some(file), ..Vma::new(start, anon_start, prot) });
+        }
+        if anon_start < end {
+            vmas.insert(Vma::new(anon_start, end, prot));
         }
+        heap_start = heap_start.max(end);
     }
 
-    page_tb.create(Process::USER_STACK_TOP - PAGESIZE, PAGESIZE, "rw")?;
+    let entry = base.wrapping_add(file_header.entry as usize);
+    if !matches!(vmas.find(entry), Some(vma) if vma.prot & PROT_EXEC != 0) {
+        return Err(ENOEXEC);
+    }
 
-    proc.heap_start = curr;
-    proc.heap_end = curr;
+    // the pages go with it if anything fails
+    let mut space = AddressSpace::new(PageTable::new(), vmas, stack_size, heap_start);
+    for &(va, pos, len, prot) in &partial {
+        let page = space.page_tb().create(va, PAGESIZE, vma::perm(prot))?;
+        let buf = unsafe { core::slice::from_raw_parts_mut(page as *mut u8, len) };
+        fs::pagecache::read(inode, pos, buf)?;
+    }
+    elf::relocate(&mut space, base, &relocations)?;
+
+    let mut auxv = vec![
+        (elf::AT_PAGESZ, PAGESIZE),
+        (elf::AT_ENTRY, entry),
+        (elf::AT_PHENT, core::mem::size_of::<elf::ProgramHeader>()),
+        (elf::AT_PHNUM, prog_header_table.len()),
+    ];
+    if let Some(phdr) = elf::phdr_vaddr(&file_header, &prog_header_table) {
+        auxv.push((elf::AT_PHDR, base.wrapping_add(phdr as usize)));
+    }
+    let page = space.page_tb().create(stack, stack_size, "rw")?;
+    let sp = init_stack(page, stack, &argv, &envp, auxv);
+
+    proc.cwd.lock().get_or_insert(inode.parent);
+
+    // nothing can fail from here, the other threads go away
+    kill_other_threads(None);
+    wait_for_threads();
 
-    let user_ctx = 
     unsafe {
         let user_ctx = proc.sp_el1.as_mut_ptr() as *mut UserContext;
 
         // reset exception link register
-        (*user_ctx).elr_el1 = Process::USER_BASE_ADDR;
+        (*user_ctx).elr_el1 = entry;
         (*user_ctx).spsr_el1 = 0;
+        // nothing of the old program is left in the registers
+        (*user_ctx).x = [0; 31];
 
 
-        // reset page table
-        let x = page_tb.as_ptr() as usize;
+        // reset page table,
+        // the new address space gets a new ASID so there's no need to flush TLB
+        let x = space.renew_ttbr1();
         asm!("msr ttbr1_el1, {}",
-            "TLBI VMALLE1",
-            "dsb sy",
             "isb sy", in(reg) x);
-        user_ctx
-    };
-    core::mem::swap(&mut page_tb, &mut proc.page_tb);
-    page_tb.release();
-
-    // put argv onto stack
-    let argc = argv.len();
-    let mut v = Vec::<*const u8>::new();
-    let mut ptr = Process::USER_STACK_TOP as *mut u8;
-    let argv = unsafe {
-        for arg in argv {
-            ptr = ptr.sub(arg.len());
-            core::slice::from_raw_parts_mut(ptr, arg.len())
-                        .copy_from_slice(&arg);
-            v.push(ptr);
-        }
-        let ptr = (ptr as *mut *const u8).sub(v.len());
-        let s = core::slice::from_raw_parts_mut(ptr, v.len());
-                    s.copy_from_slice(&v);
-        ptr as usize
-    };
+        // no TLS yet
+        asm!("msr tpidr_el0, xzr");
+    }
+    // nor FP/SIMD state
+    proc.fpsimd = None;
+    fpsimd::trap_el0();
+    // the old address space is released
+    proc.mm = Some(Arc::new(Mutex::new(space)));
+
+    proc.close_on_exec();
+    proc.signal.exec();
 
     unsafe {
         // reset stack
-        asm!("msr sp_el0, {}", in(reg) argv);
-        (*user_ctx).x[1] = argv;
+        asm!("msr sp_el0, {}", in(reg) sp);
     }
-    Ok(argc)
+    // the program finds everything on the stack
+    Ok(0)
 }
 
-pub fn fork() -> Result<usize, isize> {
-    let proc = current();
+// there's room for these many entries of auxv on the stack
+const AUXV_MAX: usize = 8;
+const AT_RANDOM_SIZE: usize = 16;
 
-    let mut page_tb = PageTable::new();
+// what `init_stack` puts on the stack, at most
+fn stack_args_size(argv: &[Vec<u8>], envp: &[Vec<u8>]) -> usize {
+    let strings = argv.iter().chain(envp.iter()).map(|s| s.len()).sum::<usize>();
+    let words = 1 + argv.len() + 1 + envp.len() + 1 + 2 * AUXV_MAX;
+    AT_RANDOM_SIZE + strings + words * core::mem::size_of::<usize>() + 16
+}
 
-    // copy text data
-    let text = page_tb.create(Process::USER_BASE_ADDR,
-                                proc.heap_start - Process::USER_BASE_ADDR,
-                                "rx")? as *mut u8;
+// the stack as the System V ABI has it, sp points to argc,
+// then argv, NULL, envp, NULL and auxv pairs up to AT_NULL.
+// the strings and the bytes of AT_RANDOM are on top.
+// it's written through `page`, where the kernel sees the stack at `stack`.
+fn init_stack(page: usize, stack: usize, argv: &[Vec<u8>], envp: &[Vec<u8>], mut auxv: Vec<(usize, usize)>) -> usize {
+    let kernel = |va: usize| (page + va - stack) as *mut u8;
+    let mut top = Process::USER_STACK_TOP - AT_RANDOM_SIZE;
     unsafe {
-        core::ptr::copy_nonoverlapping(Process::USER_BASE_ADDR as *const u8,
-                                        text,
-                                        proc.heap_start - Process::USER_BASE_ADDR);
+        core::ptr::copy_nonoverlapping(random_bytes().as_ptr(), kernel(top), AT_RANDOM_SIZE);
     }
+    auxv.push((elf::AT_RANDOM, top));
+    auxv.push((elf::AT_NULL, 0));
+    assert!(auxv.len() <= AUXV_MAX);
 
-    // copy heap data
-    let heap = page_tb.create(proc.heap_start, proc.heap_end - proc.heap_start, "rw")? as *mut u8;
-    unsafe {
-        core::ptr::copy_nonoverlapping(proc.heap_start as *const u8, heap, proc.heap_end - proc.heap_start);
+    let mut place = |s: &Vec<u8>| {
+        top -= s.len();
+        unsafe {
+            core::ptr::copy_nonoverlapping(s.as_ptr(), kernel(top), s.len());
+        }
+        top
+    };
+    let argv = argv.iter().map(&mut place).collect::<Vec<_>>();
+    let envp = envp.iter().map(&mut place).collect::<Vec<_>>();
+
+    let mut words = vec![argv.len()];
+    words.extend(argv);
+    words.push(0);
+    words.extend(envp);
+    words.push(0);
+    for (ty, val) in auxv {
+        words.push(ty);
+        words.push(val);
     }
 
-    // copy user stack data
-    let stack = page_tb.create(Process::USER_STACK_TOP - proc.stack_size,
-                                proc.stack_size, "rw")? as *mut u8;
+    let sp = (top - words.len() * core::mem::size_of::<usize>()) & !0xf;
     unsafe {
-        core::ptr::copy_nonoverlapping((Process::USER_STACK_TOP - proc.stack_size) as *const u8, 
-                                        stack, proc.stack_size);
+        core::ptr::copy_nonoverlapping(words.as_ptr(), kernel(sp) as *mut usize, words.len());
     }
+    sp
+}
+
+// for AT_RANDOM. there's nothing better than the counter to go on,
+// it's spread out with splitmix64.
+fn random_bytes() -> [u8; AT_RANDOM_SIZE] {
+    let mut state = timer::now();
+    let mut next = || {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    };
+
+    let mut bytes = [0; AT_RANDOM_SIZE];
+    bytes[..8].copy_from_slice(&next().to_le_bytes());
+    bytes[8..].copy_from_slice(&next().to_le_bytes());
+    bytes
+}
+
+pub fn fork() -> Result<usize, Errno> {
+    let proc = current();
+
+    // share text, heap and stack with the child,
+    // a page is copied only when one of us writes to it
+    let space = {
+        let mut space = proc.space();
+        let mut page_tb = PageTable::new();
+        if let Err(err) = space.page_tb().share_with(&mut page_tb) {
+            page_tb.release();
+            return Err(err);
+        }
+
+        let mut new_space = AddressSpace::new(page_tb, space.vmas.clone(), space.stack_size, space.heap_start);
+        new_space.heap_end = space.heap_end;
+        new_space
+    };
+
+    // our writable pages are read-only now
+    flush_tlb();
 
     // copy user context
     let mut kernel_stack = vec![0_u8; 4 * PAGESIZE].into_boxed_slice();
@@ -387,106 +727,515 @@ pub fn fork() -> Result<usize, isize> {
     }
 
     let mut ctx = Context::new();
-    let pid = alloc_process();
     ctx.x30 = crate::exception::back_to_earth as *const fn() as usize;
     unsafe {
         asm!("mrs {}, sp_el0", out(reg) ctx.sp_el0);
+        asm!("mrs {}, tpidr_el0", out(reg) ctx.tpidr_el0);
     }
     ctx.sp_el1 = (kernel_stack.as_ptr() as usize) + 4 * PAGESIZE;
-    ctx.ttbr1 = page_tb.as_ptr() as usize | ((pid as usize) << 48);
+    // the child starts with our FP/SIMD registers
+    proc.save_fpsimd();
 
+    let new_proc = Process {
+        pid: 0,
+        tgid: 0,
+        state: ProcessState::Ready,
+        context: ctx,
+        sp_el1: kernel_stack,
+        mm: Some(Arc::new(Mutex::new(space))),
+        parent: proc.pid,
+        child: Vec::new(),
+        exit_status: 0,
+        group_exit: None,
+        clear_child_tid: 0,
+        channel: None,
+        cwd: Arc::new(Mutex::new(*proc.cwd.lock())),
+        // the open files are shared with the parent
+        file: Arc::new(Mutex::new(proc.file.lock().clone())),
+        signal: proc.signal.fork(),
+        alarm: None,
+        fpsimd: proc.fpsimd.clone(),
+    };
+
+    let mut sched = scheduler();
+    let new_proc = insert_process(new_proc);
+    new_proc.tgid = new_proc.pid;
+    let pid = new_proc.pid;
     proc.child.push(pid);
+    sched.add(pid, Some(proc.pid));
+    let cpu = sched.wake(pid, false);
+    smp::wakeup(cpu);
+
+    Ok(pid as usize)
+}
+
+pub const CLONE_VM:             usize = 0x0000100;
+pub const CLONE_FS:             usize = 0x0000200;
+pub const CLONE_FILES:          usize = 0x0000400;
+pub const CLONE_SIGHAND:        usize = 0x0000800;
+pub const CLONE_THREAD:         usize = 0x0010000;
+pub const CLONE_SETTLS:         usize = 0x0080000;
+pub const CLONE_PARENT_SETTID:  usize = 0x0100000;
+pub const CLONE_CHILD_CLEARTID: usize = 0x0200000;
+
+// processes are created by fork, clone only makes threads
+const CLONE_THREAD_FLAGS: usize = CLONE_VM | CLONE_FS | CLONE_FILES | CLONE_SIGHAND | CLONE_THREAD;
+
+// the new thread returns 0 on `stack`, we get its tid
+pub fn clone(flags: usize, stack: usize, ptid: *mut i32, tls: usize, ctid: usize) -> Result<usize, Errno> {
+    let known = CLONE_THREAD_FLAGS | CLONE_SETTLS | CLONE_PARENT_SETTID | CLONE_CHILD_CLEARTID;
+    if flags & CLONE_THREAD_FLAGS != CLONE_THREAD_FLAGS || flags & !known != 0 || stack == 0 {
+        return Err(EINVAL);
+    }
+
+    let proc = current();
+
+    // copy user context
+    let mut kernel_stack = vec![0_u8; 4 * PAGESIZE].into_boxed_slice();
+    unsafe {
+        core::ptr::copy_nonoverlapping(proc.sp_el1.as_ptr(),
+                                        kernel_stack.as_mut_ptr(),
+                                        PAGESIZE);
+        (*(kernel_stack.as_mut_ptr() as *mut UserContext)).x[0] = 0; // return value
+    }
+
+    let mut ctx = Context::new();
+    ctx.x30 = crate::exception::back_to_earth as *const fn() as usize;
+    ctx.sp_el0 = stack;
+    ctx.sp_el1 = (kernel_stack.as_ptr() as usize) + 4 * PAGESIZE;
+    match flags & CLONE_SETTLS {
+        0 => unsafe {
+            asm!("mrs {}, tpidr_el0", out(reg) ctx.tpidr_el0);
+        },
+        _ => ctx.tpidr_el0 = tls,
+    }
+    proc.save_fpsimd();
 
     let new_proc = Process {
-        pid,
+        pid: 0,
+        tgid: proc.tgid,
         state: ProcessState::Ready,
         context: ctx,
-        stack_size: proc.stack_size,
-        heap_start: proc.heap_start,
-        heap_end:  proc.heap_end,
         sp_el1: kernel_stack,
-        page_tb,
+        mm: proc.mm.clone(),
+        // only the first thread is a child of the parent
+        parent: proc.parent,
         child: Vec::new(),
+        exit_status: 0,
+        group_exit: None,
+        clear_child_tid: match flags & CLONE_CHILD_CLEARTID {
+            0 => 0,
+            _ => ctid,
+        },
         channel: None,
-        cwd: proc.cwd,
-        // FIXME
-        file: Process::default_file_dec(),
+        cwd: proc.cwd.clone(),
+        file: proc.file.clone(),
+        // the signal actions are copied, not shared
+        signal: proc.signal.fork(),
+        alarm: None,
+        fpsimd: proc.fpsimd.clone(),
     };
 
-    unsafe {
-        PROCESS_LIST[pid as usize] = Box::into_raw(Box::new(new_proc));
+    // it's not on the run queue yet, the tid is there before the thread runs.
+    // the write may fault and sleep, so it's done without the scheduler lock.
+    // the thread is there already, a bad pointer only loses the tid
+    let pid = insert_process(new_proc).pid;
+    if flags & CLONE_PARENT_SETTID != 0 && !ptid.is_null() {
+        let _ = uaccess::write_user(ptid, pid as i32);
     }
 
+    let mut sched = scheduler();
+    sched.add(pid, Some(proc.pid));
+    let cpu = sched.wake(pid, false);
+    smp::wakeup(cpu);
+
     Ok(pid as usize)
 }
 
-pub fn wait(pid: u8) -> Result<usize, isize> {
-    // must be child process
+// give it a pid, the caller puts it on the run queue
+fn insert_process(proc: Process) -> &'static mut Process {
+    let mut list = process_list();
+    let pid = alloc_pid(&list);
+    let proc = Box::into_raw(Box::new(proc));
+    list.insert(pid, proc);
+    unsafe {
+        (*proc).pid = pid;
+        &mut *proc
+    }
+}
+
+// a thread that only runs in the kernel, it's adopted by init like an orphan
+pub fn spawn_kthread(entry: fn()) -> u32 {
+    let kernel_stack = vec![0_u8; 4 * PAGESIZE].into_boxed_slice();
+
+    // `kthread_start` calls `entry` in x19
+    let mut ctx = Context::new();
+    ctx.x19 = entry as usize;
+    ctx.x30 = kthread_start as usize;
+    ctx.sp_el1 = (kernel_stack.as_ptr() as usize) + 4 * PAGESIZE;
+
+    let new_proc = Process {
+        pid: 0,
+        tgid: 0,
+        state: ProcessState::Ready,
+        context: ctx,
+        sp_el1: kernel_stack,
+        mm: None,
+        parent: 0,
+        child: Vec::new(),
+        exit_status: 0,
+        group_exit: None,
+        clear_child_tid: 0,
+        channel: None,
+        cwd: Arc::new(Mutex::new(None)),
+        file: Arc::new(Mutex::new(Vec::new())),
+        signal: SignalState::new(),
+        alarm: None,
+        fpsimd: None,
+    };
+
+    let mut sched = scheduler();
+    let new_proc = insert_process(new_proc);
+    new_proc.tgid = new_proc.pid;
+    let pid = new_proc.pid;
+    find(0).unwrap().child.push(pid);
+    sched.add(pid, None);
+    let cpu = sched.wake(pid, false);
+    smp::wakeup(cpu);
+
+    pid
+}
+
+#[no_mangle]
+extern "C" fn kthread_main(entry: usize) -> ! {
+    let entry: fn() = unsafe { core::mem::transmute(entry) };
+    // kernel threads are interruptible, see irq.rs
+    irq::enable();
+    entry();
+    exit(0);
+}
+
+pub const WNOHANG: usize = 1;
+
+// pid == -1 means any child
+pub fn wait(pid: isize, wstatus: *mut i32, options: usize) -> Result<usize, Errno> {
     let proc = current();
-    if !proc.child.iter().any(|child| *child == pid) {
-        return Err(-1)
+    loop {
+        // the children change under the scheduler lock
+        let sched = scheduler();
+        if pid != -1 && !proc.child.iter().any(|child| *child as isize == pid) {
+            // must be child process
+            return Err(ECHILD);
+        }
+
+        if proc.child.is_empty() {
+            return Err(ECHILD);
+        }
+
+        let dead = proc.child.iter().position(|&child| {
+            (pid == -1 || child as isize == pid) &&
+            matches!(find(child), Some(child) if child.state == ProcessState::Dead)
+        });
+
+        if let Some(idx) = dead {
+            let child = proc.child.swap_remove(idx);
+            drop(sched);
+            let status = reap(child);
+            if !wstatus.is_null() {
+                uaccess::write_user(wstatus, status as i32)?;
+            }
+            return Ok(child as usize);
+        }
+
+        if options & WNOHANG != 0 {
+            return Ok(0);
+        }
+
+        sleep_locked(proc.child_channel(), sched);
+
+        if signal_pending() {
+            return Err(EINTR);
+        }
     }
+}
 
-    let mut child = unsafe {
-        Box::from_raw(PROCESS_LIST[pid as usize])
+// free what is left of a dead process, returns its wait status
+fn reap(pid: u32) -> usize {
+    let child = unsafe {
+        Box::from_raw(process_list().remove(&pid).unwrap())
     };
 
-    while child.state != ProcessState::Dead {
-        sleep(pid as usize);
+    assert!(child.state == ProcessState::Dead);
+    child.exit_status
+}
+
+// the whole thread group exits
+pub fn exit(code: usize) -> ! {
+    exit_group((code & 0xff) << 8)
+}
+
+// killed by a signal, so are the other threads
+pub fn terminate(sig: usize) -> ! {
+    exit_group(sig & 0x7f)
+}
+
+// only the calling thread exits, the process is gone with the last one
+pub fn exit_thread(code: usize) -> ! {
+    exit_with((code & 0xff) << 8)
+}
+
+fn exit_group(status: usize) -> ! {
+    kill_other_threads(Some(status));
+    exit_with(status)
+}
+
+// `status` is what the parent gets, unless the group already has one
+fn kill_other_threads(status: Option<usize>) {
+    let proc = current();
+    let mut sched = scheduler();
+    if let (Some(status), Some(leader)) = (status, find(proc.tgid)) {
+        leader.group_exit.get_or_insert(status);
     }
 
-    child.page_tb.release();
-    unsafe {
-        PROCESS_LIST[pid as usize] = core::ptr::null_mut();
+    let others = process_list().values()
+                                .map(|ptr| unsafe { &**ptr })
+                                .filter(|other| other.tgid == proc.tgid && other.pid != proc.pid)
+                                .map(|other| other.pid)
+                                .collect::<Vec<_>>();
+    for pid in others {
+        let _ = signal::send_locked(&mut **sched, pid, signal::SIGKILL);
+    }
+}
+
+// the first thread stays until the others are dead
+fn wait_for_threads() {
+    let proc = current();
+    loop {
+        let sched = scheduler();
+        let alive = process_list().values()
+                                    .map(|ptr| unsafe { &**ptr })
+                                    .any(|other| other.tgid == proc.tgid &&
+                                                 other.pid != proc.pid &&
+                                                 other.state != ProcessState::Dead);
+        if !alive {
+            return;
+        }
+        sleep_locked(proc.thread_channel(), sched);
     }
-    Ok(0)
 }
 
-pub fn exit() -> ! {
+fn exit_with(status: usize) -> ! {
     let proc = current();
+    assert_ne!(proc.pid, 0, "init exited");
+
+    // wake up whoever is joining us
+    if proc.clear_child_tid != 0 {
+        let ctid = proc.clear_child_tid as *mut u32;
+        if uaccess::write_user(ctid, 0).is_ok() {
+            let _ = futex::wake(ctid, 1);
+        }
+    }
+
+    if proc.is_leader() {
+        wait_for_threads();
+    }
+
+    // release the resources right now,
+    // only the kernel stack is left to free.
+    // the address space may be freed with it, so get out first.
+    drop(core::mem::take(&mut proc.file));
+    drop(core::mem::take(&mut proc.cwd));
+    if let Some(mm) = proc.mm.take() {
+        load_kernel_table();
+        drop(mm);
+    }
+
+    let mut sched = scheduler();
+    if let Some(alarm) = proc.alarm.take() {
+        timer::cancel(alarm);
+    }
+
+    // orphans are adopted by init
+    let init = find(0).unwrap();
+    for pid in proc.child.drain(..) {
+        find(pid).unwrap().parent = 0;
+        init.child.push(pid);
+    }
+    // there may be zombies among them
+    wakeup_locked(&mut **sched, init.child_channel());
+
+    // the parent can't see it's dead until we are off the cpu
+    proc.exit_status = proc.group_exit.unwrap_or(status);
     proc.state = ProcessState::Dead;
-    wakeup(proc.pid as usize);
-    switch_to_scheduler();
+    sched.remove(proc.pid);
+
+    if !proc.is_leader() {
+        // nobody waits for a thread, the scheduler frees it
+        if let Some(leader) = find(proc.tgid) {
+            wakeup_locked(&mut **sched, leader.thread_channel());
+        }
+        switch_to_scheduler(sched);
+        panic!("error: exit");
+    }
+
+    let parent = find(proc.parent).unwrap();
+    let _ = signal::send_locked(&mut **sched, parent.pid, signal::SIGCHLD);
+    wakeup_locked(&mut **sched, parent.child_channel());
+
+    switch_to_scheduler(sched);
     panic!("error: exit");
 }
 
+// EL0 used FP/SIMD for the first time since it was put on the cpu
+pub fn fpsimd_trap() {
+    let proc = current();
+    proc.fpsimd.get_or_insert_with(|| Box::new(FpState::new())).restore();
+}
+
+// the time slice is used up
 pub fn yield_cpu() {
-    let mut proc = current();
+    let proc = current();
+    let mut sched = scheduler();
     proc.state = ProcessState::Ready;
-    switch_to_scheduler();
+    sched.expire(proc.pid);
+    switch_to_scheduler(sched);
+}
+
+// `guard` protects the condition we are waiting for.
+// it's released after we are marked as sleeping, so a wakeup in between isn't lost,
+// the waker must change the condition with it held.
+pub fn sleep_on<G>(channel: usize, guard: G) {
+    let sched = scheduler();
+    drop(guard);
+    sleep_locked(channel, sched);
 }
 
-pub fn sleep(channel: usize) {
+fn sleep_locked(channel: usize, sched: IrqMutexGuard<'static, Box<dyn Scheduler>>) {
     let proc = current();
     proc.state = ProcessState::Blocking;
     proc.channel = Some(channel);
-    switch_to_scheduler();
+    switch_to_scheduler(sched);
+}
+
+// sleep on `channel`, but give up at `deadline`, returns true if it timed out
+pub fn sleep_timeout(channel: usize, deadline: u64) -> bool {
+    sleep_timeout_on(channel, deadline, ())
+}
+
+// `sleep_timeout` with the condition protected by `guard`, like `sleep_on`
+pub fn sleep_timeout_on<G>(channel: usize, deadline: u64, guard: G) -> bool {
+    let sched = scheduler();
+    drop(guard);
+
+    // the timer is armed once we are marked as sleeping, with the scheduler lock held.
+    // another cpu may run the wheel, it would find us not sleeping yet and drop the wakeup.
+    let proc = current();
+    proc.state = ProcessState::Blocking;
+    proc.channel = Some(channel);
+    let timer = timer::add(deadline, timer::Action::Wakeup(proc.pid));
+    switch_to_scheduler(sched);
+    !timer::cancel(timer)
+}
+
+pub fn timer_expired(action: timer::Action) {
+    match action {
+        timer::Action::Wakeup(pid) => {
+            let mut sched = scheduler();
+            if let Some(proc) = find(pid) {
+                if proc.state == ProcessState::Blocking {
+                    proc.make_ready(&mut **sched, false);
+                }
+            }
+        }
+        timer::Action::Alarm(pid) => {
+            let mut sched = scheduler();
+            if let Some(proc) = find(pid) {
+                proc.alarm = None;
+                let _ = signal::send_locked(&mut **sched, pid, signal::SIGALRM);
+            }
+        }
+    }
 }
 
 pub fn wakeup(channel: usize) {
-    let list = unsafe {
-        &mut PROCESS_LIST
-    };
+    wakeup_locked(&mut **scheduler(), channel);
+}
 
-    for proc in list.iter_mut().filter(|ptr| !ptr.is_null()).map(|ptr| unsafe{&mut **ptr}) {
+fn wakeup_locked(sched: &mut dyn Scheduler, channel: usize) {
+    for proc in process_list().values().map(|ptr| unsafe { &mut **ptr }) {
         if proc.is_waiting_on(channel) {
-            proc.wakeup();
+            proc.make_ready(sched, false);
         }
     }
 }
 
-pub fn sbrk(inc: isize) -> Result<usize, isize> {
-    let proc = current();
-    if inc < 0 || proc.heap_end + inc as usize - proc.heap_start > Process::USER_HEAP_SIZE_LIMIT {
-        Err(0)
-    } else {
-        let ret = Ok(proc.heap_end);
-        proc.heap_end += inc as usize;
-        ret
+// the heap is an area from `heap_start` up to the page `heap_end` is in
+pub fn sbrk(inc: isize) -> Result<usize, Errno> {
+    let mut space = current().space();
+    let (start, old) = (space.heap_start, space.heap_end);
+    if inc < 0 || old + inc as usize - start > Process::USER_HEAP_SIZE_LIMIT {
+        return Err(ENOMEM);
+    }
+
+    // something may have been mapped right above it
+    let end = old + inc as usize;
+    if !space.vmas.is_free(round_up(old), round_up(end)) {
+        return Err(ENOMEM);
+    }
+
+    if round_up(end) > round_up(old) {
+        space.vmas.remove(start, round_up(old));
+        space.vmas.insert(Vma::new(start, round_up(end), PROT_READ | PROT_WRITE));
     }
+    space.heap_end = end;
+    Ok(old)
 }
 
-pub fn get_cwd(buf: &mut [u8]) -> Result<usize, isize> {
+pub fn mmap(addr: usize, len: usize, prot: usize, flags: usize, fd: usize, offset: usize) -> Result<usize, Errno> {
+    let file = match flags & vma::MAP_ANONYMOUS {
+        0 => Some(map_file(fd, offset, prot, flags)?),
+        _ => None,
+    };
+    current().space().mmap(addr, len, prot, flags, file)
+}
+
+// a regular file, opened for what the mapping is going to do with it
+fn map_file(fd: usize, offset: usize, prot: usize, flags: usize) -> Result<VmaFile, Errno> {
+    let file = current().get_file_desc(fd)?;
+    let inode = file.inode().ok_or(ENODEV)?;
+    if !unsafe { fs::get_inode(inode) }.is_file() {
+        return Err(ENODEV);
+    }
+    if offset % PAGESIZE != 0 {
+        return Err(EINVAL);
+    }
+
+    let shared = flags & vma::MAP_SHARED != 0;
+    let readable = file.flags() & fs::FLAGS_O_WRONLY == 0;
+    let writable = file.flags() & fs::FLAGS_O_RDONLY == 0;
+    if !readable || (shared && prot & PROT_WRITE != 0 && !writable) {
+        return Err(EACCES);
+    }
+    Ok(VmaFile { inode, offset, shared })
+}
+
+// Err with the signal if the access isn't allowed
+pub fn page_fault(addr: usize, write: bool, exec: bool) -> Result<(), usize> {
+    let mm = current().mm.clone().expect("kernel thread has no user space");
+    space::fault(&mm, addr, write, exec)
+}
+
+pub fn munmap(addr: usize, len: usize) -> Result<usize, Errno> {
+    current().space().munmap(addr, len)?;
+    Ok(0)
+}
+
+pub fn mprotect(addr: usize, len: usize, prot: usize) -> Result<usize, Errno> {
+    current().space().mprotect(addr, len, prot)?;
+    Ok(0)
+}
+
+pub fn get_cwd(buf: &mut [u8]) -> Result<usize, Errno> {
     let mut cwd = unsafe {
         current().get_cwd()
     };
@@ -517,63 +1266,285 @@ pub fn get_cwd(buf: &mut [u8]) -> Result<usize, isize> {
                         ).collect::<Vec<u8>>();
     let len = path.len();
     if len + 1 /* null-terminated */ > buf.len() {
-        return Err(0);
+        return Err(ERANGE);
     }
 
     buf[len] = 0;
 
     buf[..len].copy_from_slice(&path);
 
-    Ok(buf.as_ptr() as usize)
+    // with the nul
+    Ok(len + 1)
 }
 
-pub fn chdir(path: &[u8]) -> Result<usize, isize> {
-    let inode = fs::path_lookup(core::str::from_utf8(path).unwrap())?.num;
+pub const CLOCK_REALTIME:  usize = 0;
+pub const CLOCK_MONOTONIC: usize = 1;
+pub const TIMER_ABSTIME:   usize = 1;
+
+pub fn nanosleep(req: *const TimeSpec, rem: *mut TimeSpec) -> Result<usize, Errno> {
+    clock_nanosleep(CLOCK_MONOTONIC, 0, req, rem)
+}
+
+// there is no RTC, both clocks count from boot
+pub fn clock_nanosleep(clock: usize, flags: usize, req: *const TimeSpec, rem: *mut TimeSpec) -> Result<usize, Errno> {
+    if clock != CLOCK_REALTIME && clock != CLOCK_MONOTONIC {
+        return Err(EINVAL);
+    }
+
+    let ticks = uaccess::read_user(req)?.to_ticks().ok_or(EINVAL)?;
+    let deadline = match flags & TIMER_ABSTIME {
+        0 => timer::now().saturating_add(ticks),
+        _ => ticks,
+    };
+
+    // nobody else sleeps on it
+    let channel = core::ptr::addr_of!(deadline) as usize;
+    while timer::now() < deadline {
+        sleep_timeout(channel, deadline);
+
+        if signal_pending() {
+            if flags & TIMER_ABSTIME == 0 && !rem.is_null() {
+                let left = deadline.saturating_sub(timer::now());
+                uaccess::write_user(rem, TimeSpec::from_ticks(left))?;
+            }
+            return Err(EINTR);
+        }
+    }
+    Ok(0)
+}
+
+// returns the seconds left of the previous alarm
+pub fn alarm(seconds: usize) -> Result<usize, Errno> {
+    let proc = current();
+    let _sched = scheduler();
+    let left = match proc.alarm.take() {
+        Some(alarm) if timer::cancel(alarm) => {
+            timer::to_secs(alarm.deadline().saturating_sub(timer::now()))
+        }
+        _ => 0,
+    };
+
+    if seconds > 0 {
+        let deadline = timer::now().saturating_add(timer::from_secs(seconds as u64));
+        proc.alarm = Some(timer::add(deadline, timer::Action::Alarm(proc.pid)));
+    }
+    Ok(left as usize)
+}
+
+pub const PRIO_PROCESS: usize = 0;
+
+fn target(pid: usize) -> Result<u32, Errno> {
+    match pid {
+        0 => Ok(current().pid),
+        pid => find(pid as u32).map(|proc| proc.pid).ok_or(ESRCH),
+    }
+}
+
+// returns the new nice value
+pub fn nice(inc: isize) -> Result<usize, Errno> {
+    let pid = current().pid;
+    let nice = scheduler().nice(pid).ok_or(ESRCH)?;
+    scheduler().set_nice(pid, nice + inc)?;
+    Ok(scheduler().nice(pid).unwrap() as usize)
+}
+
+// returns 20 - nice, so it's never negative
+pub fn get_priority(which: usize, who: usize) -> Result<usize, Errno> {
+    if which != PRIO_PROCESS {
+        return Err(EINVAL);
+    }
+
+    let nice = scheduler().nice(target(who)?).ok_or(ESRCH)?;
+    Ok((20 - nice) as usize)
+}
+
+pub fn set_priority(which: usize, who: usize, nice: isize) -> Result<usize, Errno> {
+    if which != PRIO_PROCESS {
+        return Err(EINVAL);
+    }
+
+    scheduler().set_nice(target(who)?, nice)?;
+    Ok(0)
+}
+
+pub fn set_scheduler(pid: usize, policy: usize, priority: usize) -> Result<usize, Errno> {
+    scheduler().set_policy(target(pid)?, policy, priority)?;
+    Ok(0)
+}
+
+pub fn get_scheduler(pid: usize) -> Result<usize, Errno> {
+    let (policy, _) = scheduler().policy(target(pid)?).ok_or(ESRCH)?;
+    Ok(policy)
+}
+
+pub fn chdir(path: &[u8]) -> Result<usize, Errno> {
+    let path = core::str::from_utf8(path).map_err(|_| ENOENT)?;
+    let inode = fs::path_lookup(path)?.num;
     current().chdir(inode);
     Ok(0)
 }
 
+pub const F_DUPFD:         usize = 0;
+pub const F_GETFD:         usize = 1;
+pub const F_SETFD:         usize = 2;
+pub const F_GETFL:         usize = 3;
+pub const F_SETFL:         usize = 4;
+pub const F_DUPFD_CLOEXEC: usize = 1030;
+
+pub const FD_CLOEXEC:      usize = 1;
+
+pub fn dup(fd: usize) -> Result<usize, Errno> {
+    let proc = current();
+    let file = proc.get_file_desc(fd)?;
+    proc.insert_file_desc(file, false)
+}
+
+pub fn dup2(oldfd: usize, newfd: usize) -> Result<usize, Errno> {
+    if oldfd == newfd {
+        // just check whether it's valid
+        return current().get_file_desc(oldfd).map(|_| newfd);
+    }
+    dup3(oldfd, newfd, 0)
+}
+
+pub fn dup3(oldfd: usize, newfd: usize, flags: usize) -> Result<usize, Errno> {
+    if oldfd == newfd || flags & !fs::FLAGS_O_CLOEXEC != 0 {
+        return Err(EINVAL);
+    }
+
+    let proc = current();
+    let file = proc.get_file_desc(oldfd)?;
+    proc.install_file_desc(newfd, FileDesc::new(file, flags & fs::FLAGS_O_CLOEXEC != 0))?;
+    Ok(newfd)
+}
+
+pub fn fcntl(fd: usize, cmd: usize, arg: usize) -> Result<usize, Errno> {
+    let proc = current();
+    let desc = proc.file_desc(fd)?;
+
+    match cmd {
+        F_DUPFD | F_DUPFD_CLOEXEC => {
+            let desc = FileDesc::new(desc.file.clone(), cmd == F_DUPFD_CLOEXEC);
+            proc.insert_file_desc_from(arg, desc)
+        }
+        F_GETFD => Ok(if desc.cloexec { FD_CLOEXEC } else { 0 }),
+        F_SETFD => {
+            proc.set_cloexec(fd, arg & FD_CLOEXEC != 0)?;
+            Ok(0)
+        }
+        F_GETFL => Ok(desc.file.flags()),
+        F_SETFL => {
+            desc.file.set_flags(arg);
+            Ok(0)
+        }
+        _ => Err(EINVAL),
+    }
+}
+
 pub fn schedule() -> ! {
+    let cpu = cpu_id();
     loop {
-        let list = unsafe {
-            &mut PROCESS_LIST
+        clear_current();
+        // nothing else would wake up the sleepers when everyone is asleep
+        timer::run();
+
+        let mut sched = scheduler();
+        let pid = match sched.pick_next(cpu) {
+            Some(pid) => pid,
+            None => {
+                drop(sched);
+                idle();
+                continue;
+            }
         };
 
-        for ptr in list.iter_mut().map(|p| *p) {
-            clear_current();
+        let proc = match find(pid) {
+            Some(proc) if proc.is_ready() => proc,
+            _ => continue,
+        };
 
-            if ptr.is_null() {
-                continue;
-            }
+        proc.state = ProcessState::Running;
+        let slice = sched.time_slice(proc.pid);
+        drop(sched);
 
-            let proc = unsafe {
-                &mut *ptr
-            };
+        unsafe {
+            NEED_RESCHED[cpu] = false;
+        }
 
-            if proc.is_ready() {
-                proc.state = ProcessState::Running;
-                let from = unsafe {
-                    core::ptr::addr_of_mut!(SCHEDULER_CONTEXT)
-                };
-                let to = core::ptr::addr_of!(proc.context);
+        proc.context.ttbr1 = proc.ttbr1();
+        match &proc.fpsimd {
+            Some(state) => state.restore(),
+            None => fpsimd::trap_el0(),
+        }
+        timer::start_slice(timer::from_nanos(slice as u64 * TIME_SLICE_UNIT));
 
-                write_current(ptr);
+        let from = unsafe {
+            core::ptr::addr_of_mut!(SCHEDULER_CONTEXT[cpu])
+        };
+        let to = core::ptr::addr_of!(proc.context);
 
-                unsafe {
-                    switch(from, to);
-                }
-            }
+        write_current(proc);
+
+        unsafe {
+            switch(from, to);
+            // the process is off the cpu now, it left the lock for us
+            SCHEDULER.assume_init_ref().force_unlock();
         }
+
+        // a thread can't free its own kernel stack
+        if proc.state == ProcessState::Dead && !proc.is_leader() {
+            reap(proc.pid);
+        }
+    }
+}
+
+// nothing is ready, sleep until an interrupt comes.
+// interrupts are masked here, but they still end the wfi.
+fn idle() {
+    timer::stop_slice();
+
+    let start = timer::now();
+    unsafe {
+        asm!("wfi");
     }
+    timer::account_idle(timer::now() - start);
+
+    crate::exception::handle_idle_int();
 }
 
-pub fn switch_to_scheduler() {
+// `sched` is held until the scheduler is running on this cpu,
+// so no one else can pick us up before our context is saved
+pub fn switch_to_scheduler(sched: IrqMutexGuard<'static, Box<dyn Scheduler>>) {
+    core::mem::forget(sched);
+    let irq_enabled = irq::suspend();
+
     let proc = current();
+    proc.save_fpsimd();
+    fpsimd::trap_el0();
     let curr_ctx = core::ptr::addr_of_mut!(proc.context);
     unsafe {
-        let sched_ctx = core::ptr::addr_of_mut!(SCHEDULER_CONTEXT);
+        let sched_ctx = core::ptr::addr_of_mut!(SCHEDULER_CONTEXT[cpu_id()]);
         switch(curr_ctx, sched_ctx);
     }
+    irq::resume(irq_enabled);
+}
+
+// called from EL1 when the time slice is over, the switch is left to `cond_resched`
+pub fn set_need_resched() {
+    unsafe {
+        NEED_RESCHED[cpu_id()] = true;
+    }
+}
+
+// give up the cpu if the time slice ended while we were in the kernel.
+// nothing may be held, it's a switch like `yield_cpu`.
+pub fn cond_resched() {
+    let need = unsafe {
+        core::mem::replace(&mut NEED_RESCHED[cpu_id()], false)
+    };
+    if need {
+        yield_cpu();
+    }
 }
 
 fn write_current(ptr: *mut Process) {
@@ -590,6 +1561,15 @@ fn clear_current() {
     }
 }
 
+// None in the scheduler
+pub fn current_pid() -> Option<u32> {
+    let addr: usize;
+    unsafe {
+        asm!("mrs {}, CONTEXTIDR_EL1", out(reg) addr);
+        (addr as *const Process).as_ref().map(|proc| proc.pid)
+    }
+}
+
 pub fn current() -> &'static mut Process {
     let addr: usize;
     unsafe {
@@ -0,0 +1,466 @@
+// The policy that decides which process runs next.
+// It only deals with pids, so it doesn't depend on the context switch
+// and can be exercised on its own.
+
+use crate::errno::Errno::{self, EINVAL, ESRCH};
+use alloc::collections::btree_map::BTreeMap;
+use alloc::collections::vec_deque::VecDeque;
+use alloc::vec::Vec;
+
+pub const SCHED_OTHER: usize = 0;
+pub const SCHED_FIFO:  usize = 1;
+
+pub const NICE_MIN: isize = -20;
+pub const NICE_MAX: isize = 19;
+
+pub const RT_PRIORITY_MIN: usize = 1;
+pub const RT_PRIORITY_MAX: usize = 99;
+
+pub trait Scheduler {
+    // a new process, it inherits the scheduling parameters of `parent`
+    fn add(&mut self, pid: u32, parent: Option<u32>);
+    fn remove(&mut self, pid: u32);
+
+    // the process becomes ready, `interactive` means it was waiting for the user.
+    // returns the cpu whose run queue it's put on
+    fn wake(&mut self, pid: u32, interactive: bool) -> usize;
+    // the process is still ready but its time slice is used up
+    fn expire(&mut self, pid: u32);
+    fn pick_next(&mut self, cpu: usize) -> Option<u32>;
+    // in the unit of `TIME_SLICE_UNIT`
+    fn time_slice(&self, pid: u32) -> usize;
+
+    fn nice(&self, pid: u32) -> Option<isize>;
+    fn set_nice(&mut self, pid: u32, nice: isize) -> Result<(), Errno>;
+    fn policy(&self, pid: u32) -> Option<(usize, usize)>;
+    fn set_policy(&mut self, pid: u32, policy: usize, priority: usize) -> Result<(), Errno>;
+}
+
+// multi-level feedback queue
+//
+// - a new process starts at the top level
+// - a process that uses up its time slice moves one level down
+// - a process woken up by user input moves back to the top
+// - every `BOOST_INTERVAL` expired slices, everyone moves back to the top
+// - a lower level has a longer time slice, nice scales it
+// - SCHED_FIFO processes always go first, by priority then by arrival
+// - every cpu has its own run queues, a new process goes to the least loaded one
+//   and an idle cpu steals from the busiest one
+pub struct Mlfq {
+    task: BTreeMap<u32, Task>,
+    queue: Vec<RunQueue>,
+    expired: usize,
+}
+
+struct RunQueue {
+    level: [VecDeque<u32>; Mlfq::LEVELS],
+    realtime: BTreeMap<usize, VecDeque<u32>>,
+}
+
+impl RunQueue {
+    fn new() -> Self {
+        Self {
+            level: Default::default(),
+            realtime: BTreeMap::new(),
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.level.iter().chain(self.realtime.values()).map(|queue| queue.len()).sum()
+    }
+
+    fn contains(&self, pid: u32) -> bool {
+        self.level.iter().chain(self.realtime.values()).any(|queue| queue.contains(&pid))
+    }
+
+    fn remove(&mut self, pid: u32) {
+        for queue in self.level.iter_mut().chain(self.realtime.values_mut()) {
+            queue.retain(|p| *p != pid);
+        }
+    }
+
+    fn pop(&mut self) -> Option<u32> {
+        if let Some(pid) = self.realtime.values_mut().rev().find_map(|queue| queue.pop_front()) {
+            return Some(pid);
+        }
+        self.level.iter_mut().find_map(|queue| queue.pop_front())
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Task {
+    cpu: usize,
+    level: usize,
+    nice: isize,
+    policy: usize,
+    rt_priority: usize,
+}
+
+impl Mlfq {
+    const LEVELS: usize = 4;
+    const BOOST_INTERVAL: usize = 64;
+
+    pub fn new(ncpu: usize) -> Self {
+        Self {
+            task: BTreeMap::new(),
+            queue: (0..ncpu).map(|_| RunQueue::new()).collect(),
+            expired: 0,
+        }
+    }
+
+    fn least_loaded(&self) -> usize {
+        (0..self.queue.len()).min_by_key(|&cpu| self.queue[cpu].len()).unwrap()
+    }
+
+    fn enqueue(&mut self, pid: u32, front: bool) {
+        let task = match self.task.get(&pid) {
+            Some(task) => *task,
+            None => return,
+        };
+
+        let rq = &mut self.queue[task.cpu];
+        let queue = match task.policy {
+            SCHED_FIFO => rq.realtime.entry(task.rt_priority).or_default(),
+            _ => &mut rq.level[task.level],
+        };
+
+        if queue.contains(&pid) {
+            return;
+        }
+
+        match front {
+            true  => queue.push_front(pid),
+            false => queue.push_back(pid),
+        }
+    }
+
+    fn dequeue(&mut self, pid: u32) {
+        for rq in self.queue.iter_mut() {
+            rq.remove(pid);
+        }
+    }
+
+    fn boost(&mut self) {
+        for (_, task) in self.task.iter_mut() {
+            task.level = 0;
+        }
+
+        for rq in self.queue.iter_mut() {
+            let (top, rest) = rq.level.split_at_mut(1);
+            for queue in rest {
+                top[0].extend(queue.drain(..));
+            }
+        }
+    }
+
+    // take the next one of the busiest cpu
+    fn steal(&mut self, cpu: usize) -> Option<u32> {
+        let busiest = (0..self.queue.len()).filter(|&other| other != cpu)
+                                            .max_by_key(|&other| self.queue[other].len())?;
+        let pid = self.queue[busiest].pop()?;
+        if let Some(task) = self.task.get_mut(&pid) {
+            task.cpu = cpu;
+        }
+        Some(pid)
+    }
+}
+
+impl Scheduler for Mlfq {
+    fn add(&mut self, pid: u32, parent: Option<u32>) {
+        let cpu = self.least_loaded();
+        let task = match parent.and_then(|parent| self.task.get(&parent)) {
+            Some(parent) => Task { cpu, level: 0, ..*parent },
+            None => Task { cpu, level: 0, nice: 0, policy: SCHED_OTHER, rt_priority: 0 },
+        };
+        self.task.insert(pid, task);
+    }
+
+    fn remove(&mut self, pid: u32) {
+        self.dequeue(pid);
+        self.task.remove(&pid);
+    }
+
+    fn wake(&mut self, pid: u32, interactive: bool) -> usize {
+        let task = match self.task.get_mut(&pid) {
+            Some(task) => task,
+            None => return 0,
+        };
+
+        if interactive {
+            task.level = 0;
+        }
+        let cpu = task.cpu;
+        self.enqueue(pid, false);
+        cpu
+    }
+
+    fn expire(&mut self, pid: u32) {
+        let task = match self.task.get_mut(&pid) {
+            Some(task) => task,
+            None => return,
+        };
+
+        if task.policy == SCHED_FIFO {
+            // it keeps running until it gives up the cpu
+            self.enqueue(pid, true);
+            return;
+        }
+
+        task.level = (task.level + 1).min(Mlfq::LEVELS - 1);
+        self.enqueue(pid, false);
+
+        self.expired += 1;
+        if self.expired % Mlfq::BOOST_INTERVAL == 0 {
+            self.boost();
+        }
+    }
+
+    fn pick_next(&mut self, cpu: usize) -> Option<u32> {
+        self.queue[cpu].pop().or_else(|| self.steal(cpu))
+    }
+
+    fn time_slice(&self, pid: u32) -> usize {
+        let task = match self.task.get(&pid) {
+            Some(task) => task,
+            None => return 1,
+        };
+
+        // nice -20 doubles the slice, nice 19 makes it 1/20
+        let base = 1 << task.level;
+        let weight = (NICE_MAX + 1 - task.nice) as usize;
+        (base * weight / 20).max(1)
+    }
+
+    fn nice(&self, pid: u32) -> Option<isize> {
+        self.task.get(&pid).map(|task| task.nice)
+    }
+
+    fn set_nice(&mut self, pid: u32, nice: isize) -> Result<(), Errno> {
+        let task = self.task.get_mut(&pid).ok_or(ESRCH)?;
+        task.nice = nice.max(NICE_MIN).min(NICE_MAX);
+        Ok(())
+    }
+
+    fn policy(&self, pid: u32) -> Option<(usize, usize)> {
+        self.task.get(&pid).map(|task| (task.policy, task.rt_priority))
+    }
+
+    fn set_policy(&mut self, pid: u32, policy: usize, priority: usize) -> Result<(), Errno> {
+        let valid = match policy {
+            SCHED_OTHER => priority == 0,
+            SCHED_FIFO  => (RT_PRIORITY_MIN..=RT_PRIORITY_MAX).contains(&priority),
+            _ => false,
+        };
+
+        if !valid {
+            return Err(EINVAL);
+        }
+
+        // move it to the new queue if it's waiting in one
+        let queued = self.queue.iter().any(|rq| rq.contains(pid));
+        self.dequeue(pid);
+
+        let task = self.task.get_mut(&pid).ok_or(ESRCH)?;
+        task.policy = policy;
+        task.rt_priority = priority;
+
+        if queued {
+            self.enqueue(pid, false);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // pick the next one on `cpu` and let it use up its slice
+    fn run_slice(mlfq: &mut Mlfq, cpu: usize) -> u32 {
+        let pid = mlfq.pick_next(cpu).unwrap();
+        mlfq.expire(pid);
+        pid
+    }
+
+    #[test]
+    fn used_up_slice_moves_down_a_level() {
+        let mut mlfq = Mlfq::new(1);
+        mlfq.add(1, None);
+        mlfq.wake(1, false);
+
+        for level in 0..Mlfq::LEVELS + 2 {
+            let expected = level.min(Mlfq::LEVELS - 1);
+            assert_eq!(mlfq.task[&1].level, expected);
+            assert_eq!(mlfq.time_slice(1), 1 << expected);
+            run_slice(&mut mlfq, 0);
+        }
+    }
+
+    #[test]
+    fn higher_level_goes_first() {
+        let mut mlfq = Mlfq::new(1);
+        mlfq.add(1, None);
+        mlfq.add(2, None);
+        mlfq.wake(1, false);
+
+        assert_eq!(run_slice(&mut mlfq, 0), 1);
+        mlfq.wake(2, false);
+        assert_eq!(mlfq.pick_next(0), Some(2));
+        assert_eq!(mlfq.pick_next(0), Some(1));
+        assert_eq!(mlfq.pick_next(0), None);
+    }
+
+    #[test]
+    fn interactive_wakeup_goes_back_to_the_top() {
+        let mut mlfq = Mlfq::new(1);
+        mlfq.add(1, None);
+        mlfq.wake(1, false);
+        run_slice(&mut mlfq, 0);
+        run_slice(&mut mlfq, 0);
+        assert_eq!(mlfq.pick_next(0), Some(1));
+        assert_eq!(mlfq.task[&1].level, 2);
+
+        // it went to sleep for the user
+        mlfq.wake(1, true);
+        assert_eq!(mlfq.task[&1].level, 0);
+        assert_eq!(mlfq.queue[0].level[0].front(), Some(&1));
+    }
+
+    #[test]
+    fn everyone_is_boosted_periodically() {
+        let mut mlfq = Mlfq::new(1);
+        for pid in 1..=3 {
+            mlfq.add(pid, None);
+            mlfq.wake(pid, false);
+        }
+
+        for _ in 0..Mlfq::BOOST_INTERVAL - 1 {
+            run_slice(&mut mlfq, 0);
+        }
+        assert!(mlfq.task.values().all(|task| task.level > 0));
+
+        run_slice(&mut mlfq, 0);
+        assert!(mlfq.task.values().all(|task| task.level == 0));
+        assert_eq!(mlfq.queue[0].level[0].len(), 3);
+        assert!(mlfq.queue[0].level[1..].iter().all(|queue| queue.is_empty()));
+    }
+
+    #[test]
+    fn fifo_goes_before_other_by_priority_then_arrival() {
+        let mut mlfq = Mlfq::new(1);
+        for pid in 1..=4 {
+            mlfq.add(pid, None);
+        }
+        mlfq.set_policy(2, SCHED_FIFO, 10).unwrap();
+        mlfq.set_policy(3, SCHED_FIFO, 50).unwrap();
+        mlfq.set_policy(4, SCHED_FIFO, 10).unwrap();
+        for pid in 1..=4 {
+            mlfq.wake(pid, false);
+        }
+
+        assert_eq!(mlfq.pick_next(0), Some(3));
+        assert_eq!(mlfq.pick_next(0), Some(2));
+        assert_eq!(mlfq.pick_next(0), Some(4));
+        assert_eq!(mlfq.pick_next(0), Some(1));
+    }
+
+    #[test]
+    fn fifo_keeps_the_cpu_when_its_slice_is_up() {
+        let mut mlfq = Mlfq::new(1);
+        mlfq.add(1, None);
+        mlfq.add(2, None);
+        mlfq.set_policy(1, SCHED_FIFO, 10).unwrap();
+        mlfq.set_policy(2, SCHED_FIFO, 10).unwrap();
+        mlfq.wake(1, false);
+        mlfq.wake(2, false);
+
+        assert_eq!(run_slice(&mut mlfq, 0), 1);
+        assert_eq!(mlfq.pick_next(0), Some(1));
+        assert_eq!(mlfq.policy(1), Some((SCHED_FIFO, 10)));
+    }
+
+    #[test]
+    fn set_policy_checks_the_priority() {
+        let mut mlfq = Mlfq::new(1);
+        mlfq.add(1, None);
+
+        assert_eq!(mlfq.set_policy(1, SCHED_FIFO, 0), Err(EINVAL));
+        assert_eq!(mlfq.set_policy(1, SCHED_FIFO, RT_PRIORITY_MAX + 1), Err(EINVAL));
+        assert_eq!(mlfq.set_policy(1, SCHED_OTHER, 5), Err(EINVAL));
+        assert_eq!(mlfq.set_policy(1, 7, 0), Err(EINVAL));
+        assert_eq!(mlfq.set_policy(2, SCHED_FIFO, 10), Err(ESRCH));
+        assert_eq!(mlfq.policy(1), Some((SCHED_OTHER, 0)));
+    }
+
+    #[test]
+    fn nice_scales_the_slice() {
+        let mut mlfq = Mlfq::new(1);
+        mlfq.add(1, None);
+
+        mlfq.set_nice(1, NICE_MIN).unwrap();
+        assert_eq!(mlfq.time_slice(1), 2);
+        mlfq.set_nice(1, NICE_MAX).unwrap();
+        assert_eq!(mlfq.time_slice(1), 1);
+
+        // at the lowest level
+        mlfq.task.get_mut(&1).unwrap().level = Mlfq::LEVELS - 1;
+        let base = 1 << (Mlfq::LEVELS - 1);
+        mlfq.set_nice(1, 0).unwrap();
+        assert_eq!(mlfq.time_slice(1), base);
+        mlfq.set_nice(1, NICE_MIN).unwrap();
+        assert_eq!(mlfq.time_slice(1), base * 2);
+        mlfq.set_nice(1, 10).unwrap();
+        assert_eq!(mlfq.time_slice(1), base / 2);
+        mlfq.set_nice(1, NICE_MAX).unwrap();
+        assert_eq!(mlfq.time_slice(1), 1);
+    }
+
+    #[test]
+    fn nice_is_clamped_and_inherited() {
+        let mut mlfq = Mlfq::new(1);
+        mlfq.add(1, None);
+
+        mlfq.set_nice(1, 100).unwrap();
+        assert_eq!(mlfq.nice(1), Some(NICE_MAX));
+        mlfq.set_nice(1, -100).unwrap();
+        assert_eq!(mlfq.nice(1), Some(NICE_MIN));
+        assert_eq!(mlfq.set_nice(2, 0), Err(ESRCH));
+
+        mlfq.set_policy(1, SCHED_FIFO, 20).unwrap();
+        mlfq.add(2, Some(1));
+        assert_eq!(mlfq.nice(2), Some(NICE_MIN));
+        assert_eq!(mlfq.policy(2), Some((SCHED_FIFO, 20)));
+    }
+
+    #[test]
+    fn new_process_goes_to_the_least_loaded_cpu() {
+        let mut mlfq = Mlfq::new(2);
+        mlfq.add(1, None);
+        assert_eq!(mlfq.wake(1, false), 0);
+        mlfq.add(2, None);
+        assert_eq!(mlfq.wake(2, false), 1);
+        mlfq.add(3, None);
+        assert_eq!(mlfq.wake(3, false), 0);
+    }
+
+    #[test]
+    fn idle_cpu_steals_from_the_busiest() {
+        let mut mlfq = Mlfq::new(3);
+        for pid in 1..=4 {
+            mlfq.add(pid, None);
+            mlfq.wake(pid, false);
+        }
+        // cpu 0 has 1 and 4, cpu 1 has 2, cpu 2 has 3
+        assert_eq!(mlfq.pick_next(2), Some(3));
+        assert_eq!(mlfq.pick_next(2), Some(1));
+
+        // it stays where it was stolen to
+        assert_eq!(mlfq.task[&1].cpu, 2);
+        assert_eq!(mlfq.wake(1, false), 2);
+
+        mlfq.remove(1);
+        mlfq.remove(2);
+        assert_eq!(mlfq.pick_next(2), Some(4));
+        assert_eq!(mlfq.pick_next(2), None);
+    }
+}
@@ -0,0 +1,57 @@
+use alloc::vec::Vec;
+use crate::errno::Errno::{self, ENOEXEC};
+use crate::fs::{inode::Inode, pagecache};
+
+// how much of the `#!` line is looked at
+const LINE_MAX: usize = 256;
+
+// a script can name another script as its interpreter, up to this deep
+pub const MAX_DEPTH: usize = 4;
+
+// the interpreter of a script and its argument, None if it's not a script.
+// the argument is whatever follows the interpreter on the line, spaces and all.
+pub fn read_interpreter(inode: &Inode) -> Result<Option<(Vec<u8>, Option<Vec<u8>>)>, Errno> {
+    let mut buf = [0; LINE_MAX];
+    let len = pagecache::read(inode, 0, &mut buf)?;
+    if !buf[..len].starts_with(b"#!") {
+        return Ok(None);
+    }
+
+    let line = match buf[2..len].iter().position(|&c| c == b'\n') {
+        Some(end) => &buf[2..2 + end],
+        None if len < LINE_MAX => &buf[2..len],
+        // the line goes on too long
+        None => return Err(ENOEXEC),
+    };
+
+    let line = trim(line);
+    let (interpreter, arg) = match line.iter().position(|&c| is_blank(c)) {
+        Some(i) => (&line[..i], trim(&line[i..])),
+        None => (line, &[][..]),
+    };
+    if interpreter.is_empty() {
+        return Err(ENOEXEC);
+    }
+
+    Ok(Some((interpreter.to_vec(), (!arg.is_empty()).then(|| arg.to_vec()))))
+}
+
+fn is_blank(c: u8) -> bool {
+    c == b' ' || c == b'\t'
+}
+
+fn trim(mut s: &[u8]) -> &[u8] {
+    while let [c, rest @ ..] = s {
+        if !is_blank(*c) {
+            break;
+        }
+        s = rest;
+    }
+    while let [rest @ .., c] = s {
+        if !is_blank(*c) {
+            break;
+        }
+        s = rest;
+    }
+    s
+}
@@ -0,0 +1,416 @@
+use super::*;
+use crate::errno::Errno::{self, EINVAL, EPERM, ESRCH};
+use crate::uaccess;
+
+pub const NSIG:     usize = 32;
+
+pub const SIGINT:   usize = 2;
+pub const SIGILL:   usize = 4;
+pub const SIGTRAP:  usize = 5;
+pub const SIGBUS:   usize = 7;
+pub const SIGFPE:   usize = 8;
+pub const SIGKILL:  usize = 9;
+pub const SIGSEGV:  usize = 11;
+pub const SIGPIPE:  usize = 13;
+pub const SIGALRM:  usize = 14;
+pub const SIGCHLD:  usize = 17;
+pub const SIGCONT:  usize = 18;
+pub const SIGSTOP:  usize = 19;
+pub const SIGTSTP:  usize = 20;
+pub const SIGTTIN:  usize = 21;
+pub const SIGTTOU:  usize = 22;
+pub const SIGURG:   usize = 23;
+pub const SIGWINCH: usize = 28;
+
+pub const SIG_DFL: usize = 0;
+pub const SIG_IGN: usize = 1;
+
+pub const SIG_BLOCK:   usize = 0;
+pub const SIG_UNBLOCK: usize = 1;
+pub const SIG_SETMASK: usize = 2;
+
+pub const SA_NODEFER:   usize = 0x4000_0000;
+pub const SA_RESETHAND: usize = 0x8000_0000;
+
+// these two can't be caught, blocked or ignored
+const UNBLOCKABLE: u64 = sigmask(SIGKILL) | sigmask(SIGSTOP);
+
+// the condition flags of SPSR_EL1, the rest of a signal frame's is ignored
+const SPSR_NZCV: usize = 0xf000_0000;
+
+const fn sigmask(sig: usize) -> u64 {
+    1 << (sig - 1)
+}
+
+fn is_valid(sig: usize) -> bool {
+    (1..NSIG).contains(&sig)
+}
+
+enum DefaultAction {
+    Terminate,
+    Ignore,
+    Stop,
+    Continue,
+}
+
+fn default_action(sig: usize) -> DefaultAction {
+    match sig {
+        SIGCHLD | SIGURG | SIGWINCH           => DefaultAction::Ignore,
+        SIGSTOP | SIGTSTP | SIGTTIN | SIGTTOU => DefaultAction::Stop,
+        SIGCONT                               => DefaultAction::Continue,
+        _                                     => DefaultAction::Terminate,
+    }
+}
+
+// the layout is the same as `struct sigaction` of the C library
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct SigAction {
+    pub handler:  usize,
+    pub flags:    usize,
+    pub restorer: usize,
+    pub mask:     u64,
+}
+
+impl SigAction {
+    const fn new() -> Self {
+        Self {
+            handler:  SIG_DFL,
+            flags:    0,
+            restorer: 0,
+            mask:     0,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct SignalState {
+    pending: u64,
+    blocked: u64,
+    actions: [SigAction; NSIG],
+}
+
+impl SignalState {
+    pub const fn new() -> Self {
+        Self {
+            pending: 0,
+            blocked: 0,
+            actions: [SigAction::new(); NSIG],
+        }
+    }
+
+    // the state inherited by a forked child
+    pub fn fork(&self) -> Self {
+        Self {
+            pending: 0,
+            ..self.clone()
+        }
+    }
+
+    // caught signals are reset to default on exec, ignored ones stay ignored
+    pub fn exec(&mut self) {
+        for action in self.actions.iter_mut() {
+            if action.handler != SIG_IGN {
+                *action = SigAction::new();
+            }
+        }
+    }
+
+    pub fn has_pending(&self) -> bool {
+        self.pending & !self.blocked != 0
+    }
+
+    fn next_pending(&self) -> Option<usize> {
+        let deliverable = self.pending & !self.blocked;
+        match deliverable {
+            0 => None,
+            _ => Some(deliverable.trailing_zeros() as usize + 1),
+        }
+    }
+}
+
+// saved on the user stack while the handler is running
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct SignalFrame {
+    elr_el1:  usize,
+    spsr_el1: usize,
+    x:        [usize; 31],
+    sp_el0:   usize,
+    blocked:  u64,
+    // the handler may use FP/SIMD as well
+    has_fpsimd: usize,
+    fpsimd:   FpState,
+}
+
+// the process which gets SIGINT on Ctrl-C
+static FOREGROUND: Mutex<Option<u32>> = Mutex::new(None);
+
+pub fn set_foreground(pid: u32) {
+    *FOREGROUND.lock() = Some(pid);
+}
+
+// Ctrl-C from the serial port
+pub fn interrupt_foreground() {
+    let foreground = *FOREGROUND.lock();
+    if let Some(pid) = foreground {
+        let _ = send(pid, SIGINT);
+    }
+}
+
+pub fn send(pid: u32, sig: usize) -> Result<usize, Errno> {
+    send_locked(&mut **scheduler(), pid, sig)
+}
+
+// with the scheduler lock held
+pub fn send_locked(sched: &mut dyn Scheduler, pid: u32, sig: usize) -> Result<usize, Errno> {
+    if sig != 0 && !is_valid(sig) {
+        return Err(EINVAL);
+    }
+
+    let proc = find(pid).ok_or(ESRCH)?;
+    if sig == 0 || proc.state == ProcessState::Dead {
+        // just check whether the process exists
+        return Ok(0);
+    }
+
+    // kernel threads don't take signals
+    if proc.is_kernel_thread() {
+        return Err(EPERM);
+    }
+
+    // init only gets the signals it has a handler for, nothing kills or stops it
+    if proc.pid == 0 && proc.signal.actions[sig - 1].handler == SIG_DFL {
+        return Ok(0);
+    }
+
+    match sig {
+        SIGCONT => {
+            proc.signal.pending &= !(sigmask(SIGSTOP) | sigmask(SIGTSTP) |
+                                     sigmask(SIGTTIN) | sigmask(SIGTTOU));
+            if proc.state == ProcessState::Stopped {
+                proc.make_ready(sched, false);
+            }
+        }
+        SIGSTOP | SIGTSTP | SIGTTIN | SIGTTOU => {
+            proc.signal.pending &= !sigmask(SIGCONT);
+        }
+        _ => {}
+    }
+
+    // an ignored signal is discarded right away
+    let ignored = match proc.signal.actions[sig - 1].handler {
+        SIG_IGN => true,
+        SIG_DFL => matches!(default_action(sig), DefaultAction::Ignore | DefaultAction::Continue),
+        _ => false,
+    };
+
+    if ignored && sig != SIGKILL && sig != SIGSTOP {
+        return Ok(0);
+    }
+
+    proc.signal.pending |= sigmask(sig);
+
+    // interrupt the sleep, the process will notice the signal after it's woken up
+    if proc.state == ProcessState::Blocking && proc.signal.has_pending() {
+        proc.make_ready(sched, false);
+    }
+
+    // SIGKILL also wakes up a stopped process
+    if sig == SIGKILL && proc.state == ProcessState::Stopped {
+        proc.make_ready(sched, false);
+    }
+    Ok(0)
+}
+
+// the current thread can't get past a fault, the signal can't be blocked or ignored.
+// returns true if it will be killed by it.
+pub fn force(sig: usize) -> bool {
+    let proc = current();
+    let _sched = scheduler();
+    let action = &mut proc.signal.actions[sig - 1];
+    if action.handler == SIG_IGN || proc.signal.blocked & sigmask(sig) != 0 {
+        *action = SigAction::new();
+    }
+    proc.signal.blocked &= !sigmask(sig);
+    proc.signal.pending |= sigmask(sig);
+    action.handler == SIG_DFL
+}
+
+pub fn sigaction(sig: usize, act: *const SigAction, oldact: *mut SigAction) -> Result<usize, Errno> {
+    if !is_valid(sig) {
+        return Err(EINVAL);
+    }
+
+    // user memory isn't touched with the lock held, it may fault
+    let new = match act.is_null() {
+        true => None,
+        false if sig == SIGKILL || sig == SIGSTOP => return Err(EINVAL),
+        false => Some(uaccess::read_user(act)?),
+    };
+
+    let old = {
+        let _sched = scheduler();
+        let action = &mut current().signal.actions[sig - 1];
+        let old = *action;
+        if let Some(new) = new {
+            *action = new;
+        }
+        old
+    };
+
+    if !oldact.is_null() {
+        uaccess::write_user(oldact, old)?;
+    }
+    Ok(0)
+}
+
+pub fn sigprocmask(how: usize, set: *const u64, oldset: *mut u64) -> Result<usize, Errno> {
+    let set = match set.is_null() {
+        true => None,
+        false => Some(uaccess::read_user(set)?),
+    };
+
+    let old = {
+        let _sched = scheduler();
+        let signal = &mut current().signal;
+        let old = signal.blocked;
+        if let Some(set) = set {
+            signal.blocked = match how {
+                SIG_BLOCK   => signal.blocked | set,
+                SIG_UNBLOCK => signal.blocked & !set,
+                SIG_SETMASK => set,
+                _ => return Err(EINVAL),
+            } & !UNBLOCKABLE;
+        }
+        old
+    };
+
+    if !oldset.is_null() {
+        uaccess::write_user(oldset, old)?;
+    }
+    Ok(0)
+}
+
+// deliver pending signals before returning to user space
+pub fn deliver() {
+    loop {
+        let proc = current();
+        let sched = scheduler();
+        let sig = match proc.signal.next_pending() {
+            Some(sig) => sig,
+            None => return,
+        };
+        proc.signal.pending &= !sigmask(sig);
+
+        let action = proc.signal.actions[sig - 1];
+        match action.handler {
+            SIG_IGN => {}
+            SIG_DFL => match default_action(sig) {
+                DefaultAction::Ignore | DefaultAction::Continue => {}
+                DefaultAction::Terminate => {
+                    drop(sched);
+                    terminate(sig);
+                }
+                DefaultAction::Stop => {
+                    proc.state = ProcessState::Stopped;
+                    switch_to_scheduler(sched);
+                }
+            }
+            _ => {
+                drop(sched);
+                // no room for the frame on the stack
+                if setup_frame(proc, sig, &action).is_err() {
+                    terminate(SIGSEGV);
+                }
+                return;
+            }
+        }
+    }
+}
+
+fn setup_frame(proc: &mut Process, sig: usize, action: &SigAction) -> Result<(), Errno> {
+    let uctx = proc.user_context();
+
+    let sp: usize;
+    unsafe {
+        asm!("mrs {}, sp_el0", out(reg) sp);
+    }
+
+    proc.save_fpsimd();
+
+    let frame_ptr = round_down_with(sp - core::mem::size_of::<SignalFrame>(), 16);
+    let frame = SignalFrame {
+        elr_el1:  uctx.elr_el1,
+        spsr_el1: uctx.spsr_el1,
+        x:        uctx.x,
+        sp_el0:   sp,
+        blocked:  proc.signal.blocked,
+        has_fpsimd: proc.fpsimd.is_some() as usize,
+        fpsimd:   proc.fpsimd.as_deref().copied().unwrap_or_else(FpState::new),
+    };
+
+    uaccess::write_user(frame_ptr as *mut SignalFrame, frame)?;
+    unsafe {
+        asm!("msr sp_el0, {}", in(reg) frame_ptr);
+    }
+
+    // the handler returns to the restorer, which calls sigreturn
+    uctx.elr_el1 = action.handler;
+    uctx.x[0] = sig;
+    uctx.x[30] = action.restorer;
+
+    // `send_locked` looks at them from other cpus
+    let _sched = scheduler();
+    if action.flags & SA_NODEFER == 0 {
+        proc.signal.blocked |= sigmask(sig);
+    }
+    proc.signal.blocked |= action.mask & !UNBLOCKABLE;
+
+    if action.flags & SA_RESETHAND != 0 {
+        proc.signal.actions[sig - 1] = SigAction::new();
+    }
+    Ok(())
+}
+
+pub fn sigreturn() -> Result<usize, Errno> {
+    let proc = current();
+
+    let sp: usize;
+    unsafe {
+        asm!("mrs {}, sp_el0", out(reg) sp);
+    }
+
+    let frame = match uaccess::read_user(sp as *const SignalFrame) {
+        Ok(frame) => frame,
+        Err(_) => terminate(SIGSEGV),
+    };
+
+    let uctx = proc.user_context();
+    uctx.elr_el1 = frame.elr_el1;
+    // only the condition flags are up to user space,
+    // it goes back to EL0 with interrupts unmasked whatever the frame says
+    uctx.spsr_el1 = frame.spsr_el1 & SPSR_NZCV;
+    uctx.x = frame.x;
+    {
+        let _sched = scheduler();
+        proc.signal.blocked = frame.blocked & !UNBLOCKABLE;
+    }
+
+    // the registers of the handler are thrown away
+    proc.fpsimd = match frame.has_fpsimd {
+        0 => None,
+        _ => Some(Box::new(frame.fpsimd)),
+    };
+    match &proc.fpsimd {
+        Some(state) => state.restore(),
+        None => fpsimd::trap_el0(),
+    }
+
+    unsafe {
+        asm!("msr sp_el0, {}", in(reg) frame.sp_el0);
+    }
+
+    // x0 will be overwritten with the return value
+    Ok(frame.x[0])
+}
@@ -0,0 +1,213 @@
+use crate::common::*;
+use crate::errno::Errno::{self, EFAULT, EINVAL, ENOMEM};
+use crate::fs::{self, pagecache};
+use crate::uaccess;
+use crate::vm::{asid, PageTable};
+use spin::Mutex;
+use super::Process;
+use super::signal::{SIGBUS, SIGSEGV};
+use super::vma::*;
+
+// the user address space, shared by the threads of a process
+pub struct AddressSpace {
+    page_tb: PageTable,
+    // tagged with the generation
+    asid: usize,
+    pub vmas: VmaList,
+    pub stack_size: usize,
+    pub heap_start: usize,
+    pub heap_end: usize,
+}
+
+impl AddressSpace {
+    pub fn new(page_tb: PageTable, vmas: VmaList, stack_size: usize, heap_start: usize) -> Self {
+        Self {
+            page_tb,
+            asid: 0,
+            vmas,
+            stack_size,
+            heap_start,
+            heap_end: heap_start,
+        }
+    }
+
+    pub fn page_tb(&mut self) -> &mut PageTable {
+        &mut self.page_tb
+    }
+
+    pub fn ttbr1(&mut self) -> usize {
+        self.page_tb.as_ptr() as usize | (asid::check(&mut self.asid) << 48)
+    }
+
+    // the translations of the old address space may still be in the TLB
+    pub fn renew_ttbr1(&mut self) -> usize {
+        self.page_tb.as_ptr() as usize | (asid::renew(&mut self.asid) << 48)
+    }
+
+    // the heap has its limit, the stack is right below the top
+    fn mmap_range(&self) -> (usize, usize) {
+        (self.heap_start + Process::USER_HEAP_SIZE_LIMIT, Process::USER_STACK_TOP - self.stack_size)
+    }
+
+    // the mappings go down from the stack,
+    // `addr` is only a hint without MAP_FIXED.
+    // anonymous memory can't be shared yet.
+    pub fn mmap(&mut self, addr: usize, len: usize, prot: usize, flags: usize, file: Option<VmaFile>) -> Result<usize, Errno> {
+        if len == 0 || prot & !(PROT_READ | PROT_WRITE | PROT_EXEC) != 0 {
+            return Err(EINVAL);
+        }
+        match flags & (MAP_SHARED | MAP_PRIVATE) {
+            MAP_SHARED if file.is_some() => {}
+            MAP_PRIVATE => {}
+            _ => return Err(EINVAL),
+        }
+        if len > Process::USER_STACK_TOP - Process::USER_BASE_ADDR {
+            return Err(ENOMEM);
+        }
+
+        let len = round_up(len);
+        let fits = addr % PAGESIZE == 0 && uaccess::access_ok(addr, len);
+        let start = if flags & MAP_FIXED != 0 {
+            if !fits {
+                return Err(EINVAL);
+            }
+            // whatever was there is gone
+            self.unmap(addr, addr + len);
+            addr
+        } else if fits && self.vmas.is_free(addr, addr + len) {
+            addr
+        } else {
+            let (low, high) = self.mmap_range();
+            self.vmas.find_free(len, low, high).ok_or(ENOMEM)?
+        };
+
+        self.vmas.insert(Vma { file, ..Vma::new(start, start + len, prot) });
+        Ok(start)
+    }
+
+    pub fn munmap(&mut self, addr: usize, len: usize) -> Result<(), Errno> {
+        if len == 0 || addr % PAGESIZE != 0 || !uaccess::access_ok(addr, len) {
+            return Err(EINVAL);
+        }
+
+        self.unmap(addr, addr + round_up(len));
+        Ok(())
+    }
+
+    // the whole range must be mapped
+    pub fn mprotect(&mut self, addr: usize, len: usize, prot: usize) -> Result<(), Errno> {
+        if addr % PAGESIZE != 0 || prot & !(PROT_READ | PROT_WRITE | PROT_EXEC) != 0 {
+            return Err(EINVAL);
+        }
+        if !uaccess::access_ok(addr, len) {
+            return Err(ENOMEM);
+        }
+
+        let end = addr + round_up(len);
+        if !self.vmas.is_mapped(addr, end) {
+            return Err(ENOMEM);
+        }
+
+        self.vmas.protect(addr, end, prot);
+        self.page_tb.protect(addr, end - addr, perm(prot));
+        Ok(())
+    }
+
+    // a page of its own at `va`, in the kernel, for the loader to write to
+    // before the program runs. it's brought in like `fault` would.
+    pub fn private_page(&mut self, va: usize) -> Result<usize, Errno> {
+        let va = round_down(va);
+        if let Some(page) = self.page_tb.translate(va) {
+            return Ok(page);
+        }
+
+        let vma = *self.vmas.find(va).ok_or(EFAULT)?;
+        let page = self.page_tb.create(va, PAGESIZE, perm(vma.prot))?;
+        if let Some((inode, idx)) = vma.file_page(va) {
+            let pa = pagecache::get(inode, idx)?;
+            unsafe {
+                core::ptr::copy_nonoverlapping(pa as *const u8, page as *mut u8, PAGESIZE);
+            }
+        }
+        Ok(page)
+    }
+
+    fn unmap(&mut self, start: usize, end: usize) {
+        self.vmas.remove(start, end);
+        self.page_tb.unmap(start, end - start);
+    }
+}
+
+// a user page isn't there or can't be written yet, Err with the signal
+// if the access isn't allowed. the area it's in decides what to do.
+// the address space is let go while a page of a file is read in,
+// if it changes meanwhile, the access is simply tried again.
+pub fn fault(mm: &Mutex<AddressSpace>, addr: usize, write: bool, exec: bool) -> Result<(), usize> {
+    let va = round_down(addr);
+    let mut space = mm.lock();
+    let vma = match space.vmas.find(addr) {
+        Some(vma) if vma.allows(write, exec) => *vma,
+        _ => return Err(SIGSEGV),
+    };
+
+    let page_tb = space.page_tb();
+    if page_tb.is_accessible(va, write) {
+        // another thread has handled it
+        return Ok(());
+    }
+
+    let present = page_tb.translate(va).is_some();
+    let file = match (vma.file, present) {
+        (None, false) => {
+            return page_tb.create(va, PAGESIZE, perm(vma.prot)).map(|_| ()).map_err(|_| SIGBUS);
+        }
+        (Some(file), true) if file.shared => {
+            // the first write since it was mapped
+            let (inode, idx) = vma.file_page(va).unwrap();
+            pagecache::mark_dirty(inode, idx);
+            page_tb.make_writable(va);
+            return Ok(());
+        }
+        // copy-on-write page
+        (_, true) => {
+            return page_tb.copy_on_write(va).map_err(|err| match err {
+                Errno::ENOMEM => SIGBUS,
+                _ => SIGSEGV,
+            });
+        }
+        (Some(file), false) => file,
+    };
+
+    // past the page the file ends in
+    let (inode, idx) = vma.file_page(va).unwrap();
+    if idx * PAGESIZE >= round_up(unsafe { fs::get_inode(inode) }.size() as usize) {
+        return Err(SIGBUS);
+    }
+
+    drop(space);
+    let pa = pagecache::get(inode, idx).map_err(|_| SIGBUS)?;
+    let mut space = mm.lock();
+
+    let same = matches!(space.vmas.find(addr), Some(now) if now.file_page(va) == Some((inode, idx)));
+    if !same || space.page_tb().translate(va).is_some() {
+        return Ok(());
+    }
+
+    // a shared page is written to the file, it's writable only once that's noted
+    let prot = match file.shared && !write {
+        true  => vma.prot & !PROT_WRITE,
+        false => vma.prot,
+    };
+    if file.shared && write {
+        pagecache::mark_dirty(inode, idx);
+    }
+    space.page_tb().map_page(va, pa, perm(prot), file.shared);
+    Ok(())
+}
+
+// the last thread is gone, it must not be in TTBR1_EL1 of any cpu
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        self.page_tb.release();
+    }
+}
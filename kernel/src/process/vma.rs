@@ -0,0 +1,157 @@
+use alloc::collections::btree_map::BTreeMap;
+use crate::common::PAGESIZE;
+
+pub const PROT_NONE:     usize = 0;
+pub const PROT_READ:     usize = 1;
+pub const PROT_WRITE:    usize = 2;
+pub const PROT_EXEC:     usize = 4;
+
+pub const MAP_SHARED:    usize = 0x01;
+pub const MAP_PRIVATE:   usize = 0x02;
+pub const MAP_FIXED:     usize = 0x10;
+pub const MAP_ANONYMOUS: usize = 0x20;
+
+// the permission of the pages, EL0 can't have write or execute without read
+pub fn perm(prot: usize) -> &'static str {
+    match (prot & PROT_WRITE != 0, prot & PROT_EXEC != 0) {
+        _ if prot == PROT_NONE => "",
+        (true, true)   => "rwx",
+        (true, false)  => "rw",
+        (false, true)  => "rx",
+        (false, false) => "r",
+    }
+}
+
+// the part of a regular file an area maps, its pages come from the page cache
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct VmaFile {
+    pub inode: u32,
+    // of the start of the area, page aligned
+    pub offset: usize,
+    // MAP_SHARED, the writes go to the file
+    pub shared: bool,
+}
+
+// a page aligned range of user space with the same protection,
+// its pages are there only once they have been touched
+#[derive(Clone, Copy, Debug)]
+pub struct Vma {
+    pub start: usize,
+    pub end: usize,
+    pub prot: usize,
+    // anonymous memory if there is none
+    pub file: Option<VmaFile>,
+}
+
+impl Vma {
+    pub fn new(start: usize, end: usize, prot: usize) -> Self {
+        Self { start, end, prot, file: None }
+    }
+
+    // the inode and the index in the page cache of the page at `va`
+    pub fn file_page(&self, va: usize) -> Option<(u32, usize)> {
+        self.file.map(|file| (file.inode, (file.offset + va - self.start) / PAGESIZE))
+    }
+
+    // whether an access can be let through
+    pub fn allows(&self, write: bool, exec: bool) -> bool {
+        match (write, exec) {
+            (true, _) => self.prot & PROT_WRITE != 0,
+            (_, true) => self.prot & PROT_EXEC != 0,
+            _ => self.prot != PROT_NONE,
+        }
+    }
+}
+
+// the areas of an address space, they never overlap
+#[derive(Clone, Default)]
+pub struct VmaList {
+    // by the start address
+    map: BTreeMap<usize, Vma>,
+}
+
+impl VmaList {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // the area `addr` is in
+    pub fn find(&self, addr: usize) -> Option<&Vma> {
+        self.map.range(..=addr)
+                .next_back()
+                .map(|(_, vma)| vma)
+                .filter(|vma| addr < vma.end)
+    }
+
+    // whether nothing is in [start, end)
+    pub fn is_free(&self, start: usize, end: usize) -> bool {
+        match self.map.range(..end).next_back() {
+            Some((_, vma)) => vma.end <= start,
+            None => true,
+        }
+    }
+
+    // whether [start, end) is covered without a hole
+    pub fn is_mapped(&self, start: usize, end: usize) -> bool {
+        let mut addr = start;
+        while addr < end {
+            match self.find(addr) {
+                Some(vma) => addr = vma.end,
+                None => return false,
+            }
+        }
+        true
+    }
+
+    pub fn insert(&mut self, vma: Vma) {
+        assert!(self.is_free(vma.start, vma.end), "vma: 0x{:x} overlaps", vma.start);
+        self.map.insert(vma.start, vma);
+    }
+
+    // the areas across the ends of the range are cut
+    pub fn remove(&mut self, start: usize, end: usize) {
+        self.split(start);
+        self.split(end);
+        let inside = self.map.range(start..end).map(|(&start, _)| start).collect::<alloc::vec::Vec<_>>();
+        for start in inside {
+            self.map.remove(&start);
+        }
+    }
+
+    pub fn protect(&mut self, start: usize, end: usize, prot: usize) {
+        self.split(start);
+        self.split(end);
+        for (_, vma) in self.map.range_mut(start..end) {
+            vma.prot = prot;
+        }
+    }
+
+    // the highest `len` bytes that are free in [low, high)
+    pub fn find_free(&self, len: usize, low: usize, high: usize) -> Option<usize> {
+        let mut top = high;
+        for (_, vma) in self.map.range(..high).rev() {
+            if top >= vma.end.max(low) + len {
+                return Some(top - len);
+            }
+            top = top.min(vma.start);
+            if top < low + len {
+                return None;
+            }
+        }
+        (top >= low + len).then(|| top - len)
+    }
+
+    // the area across `addr` becomes two
+    fn split(&mut self, addr: usize) {
+        if let Some(&vma) = self.find(addr) {
+            if vma.start < addr {
+                let mut upper = Vma { start: addr, ..vma };
+                if let Some(file) = &mut upper.file {
+                    file.offset += addr - vma.start;
+                }
+                self.map.insert(vma.start, Vma { end: addr, ..vma });
+                self.map.insert(addr, upper);
+            }
+        }
+    }
+}
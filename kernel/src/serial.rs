@@ -1,8 +1,10 @@
 use core::fmt;
 use bitflags::bitflags;
 use core::ptr;
+use alloc::vec::Vec;
 use crate::gic;
 use crate::process;
+use crate::workqueue;
 
 const UART: usize = 0x09000000;
 
@@ -136,11 +138,25 @@ impl SerialPort {
         UartFrFlags::from_bits_truncate(self.read_reg(self.flag_reg))
     }
 
+    // drain the FIFO to clear the interrupt, the input is handled by the workqueue
     pub fn receive(&mut self) {
+        let mut input = Vec::new();
         while self.line_sts().contains(UartFrFlags::RXFF) {
-            let c = self.read_reg(self.data_reg) as u8;
-            process::put_user_input(c);
+            input.push(self.read_reg(self.data_reg) as u8);
         }
+
+        workqueue::schedule(move || {
+            for c in input {
+                match c {
+                    // Ctrl-C
+                    0x03 => {
+                        SerialPort::new().write(b"^C\n");
+                        process::signal::interrupt_foreground();
+                    }
+                    _ => process::put_user_input(c),
+                }
+            }
+        });
     }
 
     pub fn send(&mut self, data: u8) {
@@ -182,6 +198,9 @@ impl SerialPort {
     }
 }
 
+// keeps the lines printed by different cpus apart
+pub static PRINT_LOCK: crate::irq::IrqMutex<()> = crate::irq::IrqMutex::new(());
+
 pub fn init() {
     SerialPort::new().init(true);
 }
@@ -199,6 +218,7 @@ impl fmt::Write for SerialPort {
 macro_rules! print {
     ($($arg:tt)+) => ({
         use core::fmt::Write;
+        let _guard = crate::serial::PRINT_LOCK.lock();
         let _ = write!(crate::serial::SerialPort::new(), $($arg)*);
     });
 }
@@ -207,6 +227,7 @@ macro_rules! print {
 macro_rules! println {
     ($($arg:tt)+) => ({
         use core::fmt::Write;
+        let _guard = crate::serial::PRINT_LOCK.lock();
         let _ = writeln!(crate::serial::SerialPort::new(), $($arg)*);
     });
 }
\ No newline at end of file
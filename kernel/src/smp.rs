@@ -0,0 +1,73 @@
+use alloc::vec;
+use crate::common::*;
+use crate::gic;
+use crate::vm;
+
+// at most this many cpus are brought up
+pub const NCPU: usize = 4;
+
+// sent to a cpu when a process is put on its run queue
+pub const SGI_WAKEUP: u32 = 0;
+
+const PSCI_CPU_ON: usize = 0xc400_0003;
+const CPU_STACK_SIZE: usize = 4 * PAGESIZE;
+
+extern "C" {
+    fn secondary_startup();
+    fn psci_call(func: usize, arg0: usize, arg1: usize, arg2: usize) -> isize;
+}
+
+pub fn cpu_id() -> usize {
+    let mpidr: usize;
+    unsafe {
+        asm!("mrs {}, MPIDR_EL1", out(reg) mpidr);
+    }
+    // Aff0, qemu numbers the cpus from 0
+    mpidr & 0xff
+}
+
+pub fn ncpu() -> usize {
+    gic::ncpus().min(NCPU)
+}
+
+// the firmware starts the cpu at `entry` with the MMU off and `context` in x0
+fn psci_cpu_on(cpu: usize, entry: usize, context: usize) -> isize {
+    unsafe {
+        psci_call(PSCI_CPU_ON, cpu, entry, context)
+    }
+}
+
+pub fn clean_dcache(start: usize, len: usize) {
+    for addr in (start..start + len).step_by(64) {
+        unsafe {
+            asm!("dc civac, {}", in(reg) addr);
+        }
+    }
+    unsafe {
+        asm!("dsb sy");
+    }
+}
+
+pub fn start_secondaries() {
+    vm::clean_kernel_table();
+
+    for cpu in 1..ncpu() {
+        // the stack is never freed
+        let stack = vec![0_u8; CPU_STACK_SIZE].leak();
+        let stack_top = stack.as_ptr() as usize + CPU_STACK_SIZE;
+        // it runs with the caches off until its MMU is on
+        clean_dcache(stack.as_ptr() as usize, CPU_STACK_SIZE);
+
+        let ret = psci_cpu_on(cpu, secondary_startup as usize, stack_top);
+        if ret != 0 {
+            println!("cpu{}: failed to start, {}", cpu, ret);
+        }
+    }
+}
+
+// kick `cpu` out of wfi
+pub fn wakeup(cpu: usize) {
+    if cpu != cpu_id() {
+        gic::send_sgi(cpu, SGI_WAKEUP);
+    }
+}
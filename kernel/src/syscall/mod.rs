@@ -1,154 +1,363 @@
 use crate::exception::UserContext;
-use crate::fs::{self, file::File, FLAGS_O_DIRECTORY};
-use crate::process;
+use crate::fs::{self, file::File, FLAGS_O_CLOEXEC, FLAGS_O_DIRECTORY};
+use crate::process::{self, futex, signal};
+use crate::timer::{self, TimeSpec};
+use crate::errno::Errno::{self, E2BIG, EFAULT, ENAMETOOLONG, ENOTDIR, ENOSYS, ESRCH};
+use crate::uaccess::{self, PATH_MAX, ARG_MAX};
+use alloc::vec;
 use alloc::vec::Vec;
 
-type SyscallFnType = fn(_: &mut UserContext) -> Result<usize, isize>;
+type SyscallFnType = fn(_: &mut UserContext) -> Result<usize, Errno>;
 
 pub static SYSCALL_TABLE: &[SyscallFnType] = &[
-    sys_fork,     // 0x00
-    sys_exec,     // 0x01
-    sys_open,     // 0x02
-    sys_read,     // 0x03
-    sys_write,    // 0x04
-    sys_close,    // 0x05
-    sys_waitpid,  // 0x06
-    sys_exit,     // 0x07
-    sys_getdents, // 0x08
-    sys_sbrk,     // 0x09
-    sys_getcwd,   // 0x0A
-    sys_mkdir,    // 0x0B
-    sys_chdir,    // 0x0C
+    sys_fork,         // 0x00
+    sys_exec,         // 0x01
+    sys_open,         // 0x02
+    sys_read,         // 0x03
+    sys_write,        // 0x04
+    sys_close,        // 0x05
+    sys_waitpid,      // 0x06
+    sys_exit,         // 0x07
+    sys_getdents,     // 0x08
+    sys_sbrk,         // 0x09
+    sys_getcwd,       // 0x0A
+    sys_mkdir,        // 0x0B
+    sys_chdir,        // 0x0C
+    sys_dup,          // 0x0D
+    sys_dup2,         // 0x0E
+    sys_dup3,         // 0x0F
+    sys_fcntl,        // 0x10
+    sys_pipe,         // 0x11
+    sys_kill,         // 0x12
+    sys_sigaction,    // 0x13
+    sys_sigprocmask,  // 0x14
+    sys_sigreturn,    // 0x15
+    sys_getpid,       // 0x16
+    sys_tcsetpgrp,    // 0x17
+    sys_nice,         // 0x18
+    sys_getpriority,  // 0x19
+    sys_setpriority,  // 0x1A
+    sys_sched_setscheduler, // 0x1B
+    sys_sched_getscheduler, // 0x1C
+    sys_nanosleep,    // 0x1D
+    sys_clock_nanosleep, // 0x1E
+    sys_alarm,        // 0x1F
+    sys_uptime,       // 0x20
+    sys_clone,        // 0x21
+    sys_gettid,       // 0x22
+    sys_exit_thread,  // 0x23
+    sys_futex,        // 0x24
+    sys_mmap,         // 0x25
+    sys_munmap,       // 0x26
+    sys_mprotect,     // 0x27
+    sys_frames,       // 0x28
 ];
 
-fn string_len(ptr: *const u8) -> usize {
-    for i in 0..128 {
-        unsafe {
-            if ptr.add(i).read() == b'\x00' {
-                return i;
-            }
+// a read or write moves at most this much at once, the rest is left for the next call
+const IO_MAX: usize = 64 * 1024;
+
+fn user_path(ptr: usize) -> Result<Vec<u8>, Errno> {
+    uaccess::read_user_str(ptr, PATH_MAX)
+}
+
+// a NULL terminated array of strings, like argv.
+// the strings keep their nul, all of them must fit in ARG_MAX
+fn user_strings(mut ptr: *const usize, total: &mut usize) -> Result<Vec<Vec<u8>>, Errno> {
+    let mut strings = Vec::<Vec<u8>>::new();
+    if ptr.is_null() {
+        return Ok(strings);
+    }
+
+    loop {
+        let arg = uaccess::read_user(ptr)?;
+        if arg == 0 {
+            return Ok(strings);
+        }
+        *total += core::mem::size_of::<usize>();
+        if *total >= ARG_MAX {
+            return Err(E2BIG);
         }
+
+        let mut s = match uaccess::read_user_str(arg, ARG_MAX - *total) {
+            Err(ENAMETOOLONG) => return Err(E2BIG),
+            res => res?,
+        };
+        s.push(0);
+        *total += s.len();
+        strings.push(s);
+        ptr = ptr.wrapping_add(1);
     }
-    panic!("string too long !");
 }
 
-pub fn sys_exec(ctx: &mut UserContext) -> Result<usize, isize> {
+pub fn sys_exec(ctx: &mut UserContext) -> Result<usize, Errno> {
     // x0 is the address of the path
-    let x0 = ctx.x[0] as *const u8;
-    let len = string_len(x0);
-    let path = unsafe {
-        core::slice::from_raw_parts(x0, len)
-    };
+    let path = user_path(ctx.x[0])?;
 
-    let mut ptr = ctx.x[1] as *const *const u8;
-    let mut argv = Vec::<Vec<u8>>::new();
-    if ptr as usize != 0 {
-        unsafe {
-            while ptr.read() as usize != 0 {
-                let len = string_len(ptr.read()) + 1;
-                let s = core::slice::from_raw_parts(ptr.read(), len);
-
-                argv.push(s.to_vec());
-                ptr = ptr.add(1);
-            }
-        }
-    }
-    crate::process::exec(path, argv)
+    // argv and envp share ARG_MAX
+    let mut total = 0;
+    let argv = user_strings(ctx.x[1] as *const usize, &mut total)?;
+    let envp = user_strings(ctx.x[2] as *const usize, &mut total)?;
+    crate::process::exec(&path, argv, envp)
 }
 
-pub fn sys_fork(_: &mut UserContext) -> Result<usize, isize> {
+pub fn sys_fork(_: &mut UserContext) -> Result<usize, Errno> {
     crate::process::fork()
 }
 
-pub fn sys_open(ctx: &mut UserContext) -> Result<usize, isize> {
-    let pathname = unsafe {
-        let ptr = ctx.x[0] as *const u8;
-        let len = string_len(ptr);
-        core::slice::from_raw_parts(ptr, len)
-    };
+pub fn sys_open(ctx: &mut UserContext) -> Result<usize, Errno> {
+    let pathname = user_path(ctx.x[0])?;
 
     let flags = ctx.x[1];
-    let inode = fs::open(pathname, flags)?;
+    let inode = fs::open(&pathname, flags)?;
 
-    process::current().insert_file_desc(File::new(inode, flags))
+    process::current().insert_file_desc(File::new(inode, flags), flags & FLAGS_O_CLOEXEC != 0)
 }
 
-pub fn sys_read(ctx: &mut UserContext) -> Result<usize, isize> {
-    let file = process::current().get_file_desc_mut(ctx.x[0])?;
-    let buf = ctx.x[1] as *mut u8;
-    let count = ctx.x[2];
-
-    unsafe {
-        fs::read(file, core::slice::from_raw_parts_mut(buf, count))
+pub fn sys_read(ctx: &mut UserContext) -> Result<usize, Errno> {
+    let file = process::current().get_file_desc(ctx.x[0])?;
+    let buf = ctx.x[1];
+    let count = ctx.x[2].min(IO_MAX);
+    if !uaccess::access_ok(buf, count) {
+        return Err(EFAULT);
     }
+
+    let mut kbuf = vec![0_u8; count];
+    let len = fs::read(&file, &mut kbuf)?;
+    uaccess::copy_to_user(buf, &kbuf[..len])?;
+    Ok(len)
 }
 
-pub fn sys_write(ctx: &mut UserContext) -> Result<usize, isize> {
-    let file = process::current().get_file_desc_mut(ctx.x[0] as usize)?;
-    let ptr = ctx.x[1] as *const u8;
-    let len = ctx.x[2] as usize;
+pub fn sys_write(ctx: &mut UserContext) -> Result<usize, Errno> {
+    let file = process::current().get_file_desc(ctx.x[0] as usize)?;
+    let buf = uaccess::read_user_bytes(ctx.x[1], ctx.x[2].min(IO_MAX))?;
 
-    unsafe {
-        fs::write(file, core::slice::from_raw_parts(ptr, len))
-    }
+    fs::write(&file, &buf)
 }
 
-pub fn sys_close(_: &mut UserContext) -> Result<usize, isize> {
-    unimplemented!()
+pub fn sys_close(ctx: &mut UserContext) -> Result<usize, Errno> {
+    // the file is released along with the last reference
+    process::current().remove_file_desc(ctx.x[0])?;
+    Ok(0)
 }
 
-pub fn sys_waitpid(ctx: &mut UserContext) -> Result<usize, isize> {
-    let pid = ctx.x[0];
-    process::wait(pid as u8)
+pub fn sys_waitpid(ctx: &mut UserContext) -> Result<usize, Errno> {
+    // pid is an int
+    let pid = ctx.x[0] as i32 as isize;
+    process::wait(pid, ctx.x[1] as *mut i32, ctx.x[2])
 }
 
-pub fn sys_exit(_: &mut UserContext) -> Result<usize, isize> {
-    process::exit()
+pub fn sys_exit(ctx: &mut UserContext) -> Result<usize, Errno> {
+    process::exit(ctx.x[0])
 }
 
-pub fn sys_getdents(ctx: &mut UserContext) -> Result<usize, isize> {
-    let file = process::current().get_file_desc_mut(ctx.x[0])?;
+pub fn sys_getdents(ctx: &mut UserContext) -> Result<usize, Errno> {
+    let file = process::current().get_file_desc(ctx.x[0])?;
     if file.flags() & FLAGS_O_DIRECTORY == 0 {
-        return Err(-1);
+        return Err(ENOTDIR);
     }
 
-    let buffer = unsafe {
-        core::slice::from_raw_parts_mut(ctx.x[1] as *mut u8, ctx.x[2])
-    };
+    let buf = ctx.x[1];
+    let count = ctx.x[2].min(IO_MAX);
+    if !uaccess::access_ok(buf, count) {
+        return Err(EFAULT);
+    }
 
-    fs::read(file, buffer)
+    let mut kbuf = vec![0_u8; count];
+    let len = fs::read(&file, &mut kbuf)?;
+    uaccess::copy_to_user(buf, &kbuf[..len])?;
+    Ok(len)
 }
 
-pub fn sys_sbrk(ctx: &mut UserContext) -> Result<usize, isize> {
+pub fn sys_sbrk(ctx: &mut UserContext) -> Result<usize, Errno> {
     let inc = ctx.x[0];
     process::sbrk(inc as isize)
 }
 
-pub fn sys_getcwd(ctx: &mut UserContext) -> Result<usize, isize> {
-    let ptr = ctx.x[0] as *mut u8;
-    let len = ctx.x[1];
+pub fn sys_getcwd(ctx: &mut UserContext) -> Result<usize, Errno> {
+    let ptr = ctx.x[0];
+    let len = ctx.x[1].min(PATH_MAX);
 
-    let buf = unsafe {
-        core::slice::from_raw_parts_mut(ptr, len)
-    };
+    let mut buf = vec![0_u8; len];
+    let len = process::get_cwd(&mut buf)?;
+    uaccess::copy_to_user(ptr, &buf[..len])?;
+    Ok(ptr)
+}
+
+pub fn sys_mkdir(ctx: &mut UserContext) -> Result<usize, Errno> {
+    let path = user_path(ctx.x[0])?;
 
-    process::get_cwd(buf)
+    fs::mkdir(&path)
 }
 
-pub fn sys_mkdir(ctx: &mut UserContext) -> Result<usize, isize> {
-    let path = unsafe {
-        let ptr = ctx.x[0] as *const u8;
-        core::slice::from_raw_parts(ptr, string_len(ptr))
-    };
+pub fn sys_chdir(ctx: &mut UserContext) -> Result<usize, Errno> {
+    let path = user_path(ctx.x[0])?;
+
+    process::chdir(&path)
+}
+
+pub fn sys_dup(ctx: &mut UserContext) -> Result<usize, Errno> {
+    process::dup(ctx.x[0])
+}
 
-    fs::mkdir(path)
+pub fn sys_dup2(ctx: &mut UserContext) -> Result<usize, Errno> {
+    process::dup2(ctx.x[0], ctx.x[1])
 }
 
-pub fn sys_chdir(ctx: &mut UserContext) -> Result<usize, isize> {
-    let path = unsafe {
-        let ptr = ctx.x[0] as *const u8;
-        core::slice::from_raw_parts(ptr, string_len(ptr))
+pub fn sys_dup3(ctx: &mut UserContext) -> Result<usize, Errno> {
+    process::dup3(ctx.x[0], ctx.x[1], ctx.x[2])
+}
+
+pub fn sys_fcntl(ctx: &mut UserContext) -> Result<usize, Errno> {
+    process::fcntl(ctx.x[0], ctx.x[1], ctx.x[2])
+}
+
+pub fn sys_pipe(ctx: &mut UserContext) -> Result<usize, Errno> {
+    let fds = ctx.x[0] as *mut [i32; 2];
+    let cloexec = ctx.x[1] & FLAGS_O_CLOEXEC != 0;
+    let (reader, writer) = fs::pipe::pipe(ctx.x[1]);
+
+    let proc = process::current();
+    let rfd = proc.insert_file_desc(reader, cloexec)?;
+    let wfd = match proc.insert_file_desc(writer, cloexec) {
+        Ok(fd) => fd,
+        Err(err) => {
+            proc.remove_file_desc(rfd)?;
+            return Err(err);
+        }
     };
 
-    process::chdir(path)
-}
\ No newline at end of file
+    if let Err(err) = uaccess::write_user(fds, [rfd as i32, wfd as i32]) {
+        proc.remove_file_desc(rfd)?;
+        proc.remove_file_desc(wfd)?;
+        return Err(err);
+    }
+    Ok(0)
+}
+
+pub fn sys_kill(ctx: &mut UserContext) -> Result<usize, Errno> {
+    // pid is an int, 0 and below name process groups, there aren't any.
+    // init is pid 0 inside the kernel, it can't be reached from here.
+    let pid = ctx.x[0] as i32;
+    if pid <= 0 {
+        return Err(ESRCH);
+    }
+    signal::send(pid as u32, ctx.x[1])
+}
+
+pub fn sys_sigaction(ctx: &mut UserContext) -> Result<usize, Errno> {
+    signal::sigaction(ctx.x[0],
+                      ctx.x[1] as *const signal::SigAction,
+                      ctx.x[2] as *mut signal::SigAction)
+}
+
+pub fn sys_sigprocmask(ctx: &mut UserContext) -> Result<usize, Errno> {
+    signal::sigprocmask(ctx.x[0], ctx.x[1] as *const u64, ctx.x[2] as *mut u64)
+}
+
+pub fn sys_sigreturn(_: &mut UserContext) -> Result<usize, Errno> {
+    signal::sigreturn()
+}
+
+// the pid of a process is the tid of its first thread
+pub fn sys_getpid(_: &mut UserContext) -> Result<usize, Errno> {
+    Ok(process::current().tgid as usize)
+}
+
+// There are no process groups yet, the foreground "group" is a single process
+pub fn sys_tcsetpgrp(ctx: &mut UserContext) -> Result<usize, Errno> {
+    process::current().get_file_desc(ctx.x[0])?;
+    signal::set_foreground(ctx.x[1] as u32);
+    Ok(0)
+}
+
+pub fn sys_nice(ctx: &mut UserContext) -> Result<usize, Errno> {
+    process::nice(ctx.x[0] as i32 as isize)
+}
+
+pub fn sys_getpriority(ctx: &mut UserContext) -> Result<usize, Errno> {
+    process::get_priority(ctx.x[0], ctx.x[1])
+}
+
+pub fn sys_setpriority(ctx: &mut UserContext) -> Result<usize, Errno> {
+    process::set_priority(ctx.x[0], ctx.x[1], ctx.x[2] as i32 as isize)
+}
+
+pub fn sys_sched_setscheduler(ctx: &mut UserContext) -> Result<usize, Errno> {
+    // struct sched_param { int sched_priority; }
+    let priority = uaccess::read_user(ctx.x[2] as *const i32)?;
+    process::set_scheduler(ctx.x[0], ctx.x[1], priority as usize)
+}
+
+pub fn sys_sched_getscheduler(ctx: &mut UserContext) -> Result<usize, Errno> {
+    process::get_scheduler(ctx.x[0])
+}
+
+pub fn sys_nanosleep(ctx: &mut UserContext) -> Result<usize, Errno> {
+    process::nanosleep(ctx.x[0] as *const TimeSpec, ctx.x[1] as *mut TimeSpec)
+}
+
+pub fn sys_clock_nanosleep(ctx: &mut UserContext) -> Result<usize, Errno> {
+    process::clock_nanosleep(ctx.x[0], ctx.x[1], ctx.x[2] as *const TimeSpec, ctx.x[3] as *mut TimeSpec)
+}
+
+pub fn sys_alarm(ctx: &mut UserContext) -> Result<usize, Errno> {
+    process::alarm(ctx.x[0] as u32 as usize)
+}
+
+// like /proc/uptime, the time since boot and the time spent idle
+pub fn sys_uptime(ctx: &mut UserContext) -> Result<usize, Errno> {
+    let uptime = ctx.x[0] as *mut TimeSpec;
+    let idle = ctx.x[1] as *mut TimeSpec;
+
+    if !uptime.is_null() {
+        uaccess::write_user(uptime, TimeSpec::from_ticks(timer::uptime()))?;
+    }
+    if !idle.is_null() {
+        uaccess::write_user(idle, TimeSpec::from_ticks(timer::idle_time()))?;
+    }
+    Ok(0)
+}
+
+// the number of frames in use by user space and the page cache
+pub fn sys_frames(_: &mut UserContext) -> Result<usize, Errno> {
+    Ok(crate::mm::frame::in_use())
+}
+
+// clone(flags, stack, parent_tid, tls, child_tid)
+pub fn sys_clone(ctx: &mut UserContext) -> Result<usize, Errno> {
+    process::clone(ctx.x[0], ctx.x[1], ctx.x[2] as *mut i32, ctx.x[3], ctx.x[4])
+}
+
+pub fn sys_gettid(_: &mut UserContext) -> Result<usize, Errno> {
+    Ok(process::current().pid as usize)
+}
+
+pub fn sys_exit_thread(ctx: &mut UserContext) -> Result<usize, Errno> {
+    process::exit_thread(ctx.x[0])
+}
+
+// futex(uaddr, op, val, timeout or val2, uaddr2)
+pub fn sys_futex(ctx: &mut UserContext) -> Result<usize, Errno> {
+    let uaddr = ctx.x[0] as *const u32;
+    let val = ctx.x[2] as u32;
+
+    match ctx.x[1] & !futex::FUTEX_PRIVATE_FLAG {
+        futex::FUTEX_WAIT    => futex::wait(uaddr, val, ctx.x[3] as *const TimeSpec),
+        futex::FUTEX_WAKE    => futex::wake(uaddr, val as usize),
+        futex::FUTEX_REQUEUE => futex::requeue(uaddr, val as usize, ctx.x[4] as *const u32, ctx.x[3] as u32 as usize),
+        _ => Err(ENOSYS),
+    }
+}
+
+// mmap(addr, len, prot, flags, fd, offset)
+pub fn sys_mmap(ctx: &mut UserContext) -> Result<usize, Errno> {
+    process::mmap(ctx.x[0], ctx.x[1], ctx.x[2] as u32 as usize, ctx.x[3] as u32 as usize, ctx.x[4] as u32 as usize, ctx.x[5])
+}
+
+pub fn sys_munmap(ctx: &mut UserContext) -> Result<usize, Errno> {
+    process::munmap(ctx.x[0], ctx.x[1])
+}
+
+pub fn sys_mprotect(ctx: &mut UserContext) -> Result<usize, Errno> {
+    process::mprotect(ctx.x[0], ctx.x[1], ctx.x[2] as u32 as usize)
+}
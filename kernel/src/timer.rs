@@ -0,0 +1,264 @@
+use alloc::vec::Vec;
+use crate::irq::IrqMutex;
+use crate::process;
+use crate::smp::{cpu_id, NCPU};
+
+// pending deadlines are hashed into the slots of a wheel by their counter value.
+// a slot holds the timers of every round, the deadline tells them apart.
+const WHEEL_SIZE: usize = 64;
+// each slot covers 2^SLOT_SHIFT ticks of CNTPCT_EL0
+const SLOT_SHIFT: u32 = 16;
+
+const NSEC_PER_SEC: u64 = 1_000_000_000;
+
+static WHEEL: IrqMutex<Wheel> = IrqMutex::new(Wheel::new());
+// when the time slice of the process running on each cpu is used up
+static mut SLICE_END: [u64; NCPU] = [u64::MAX; NCPU];
+
+static mut BOOT: u64 = 0;
+// how long each cpu has spent in wfi
+static mut IDLE: [u64; NCPU] = [0; NCPU];
+
+#[derive(Clone, Copy)]
+pub enum Action {
+    // wake up the process if it's still sleeping
+    Wakeup(u32),
+    // SIGALRM
+    Alarm(u32),
+}
+
+// the layout is the same as `struct timespec` of the C library
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct TimeSpec {
+    pub tv_sec:  i64,
+    pub tv_nsec: i64,
+}
+
+impl TimeSpec {
+    // None if it's not a valid duration
+    pub fn to_ticks(&self) -> Option<u64> {
+        if self.tv_sec < 0 || !(0..NSEC_PER_SEC as i64).contains(&self.tv_nsec) {
+            return None;
+        }
+        Some(from_secs(self.tv_sec as u64).saturating_add(from_nanos(self.tv_nsec as u64)))
+    }
+
+    pub fn from_ticks(ticks: u64) -> Self {
+        let ns = to_nanos(ticks);
+        Self {
+            tv_sec:  (ns / NSEC_PER_SEC) as i64,
+            tv_nsec: (ns % NSEC_PER_SEC) as i64,
+        }
+    }
+}
+
+struct Timer {
+    id: u64,
+    deadline: u64,
+    action: Action,
+}
+
+// returned by `add`, needed to cancel the timer
+#[derive(Clone, Copy)]
+pub struct TimerHandle {
+    id: u64,
+    deadline: u64,
+    // where it's in the wheel
+    slot: usize,
+}
+
+impl TimerHandle {
+    pub fn deadline(&self) -> u64 {
+        self.deadline
+    }
+}
+
+struct Wheel {
+    slot: [Vec<Timer>; WHEEL_SIZE],
+    // the last slot that has been run
+    last: u64,
+    next_id: u64,
+}
+
+impl Wheel {
+    const fn new() -> Self {
+        const EMPTY: Vec<Timer> = Vec::new();
+        Self {
+            slot: [EMPTY; WHEEL_SIZE],
+            last: 0,
+            next_id: 0,
+        }
+    }
+
+    // a deadline in a slot that has been run already, maybe by another cpu
+    // since `now()` was read, goes in the last one, which is run again next time.
+    // otherwise it would wait for the wheel to come round.
+    fn index(&self, deadline: u64) -> usize {
+        (deadline >> SLOT_SHIFT).max(self.last) as usize % WHEEL_SIZE
+    }
+
+    fn add(&mut self, deadline: u64, action: Action) -> TimerHandle {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        let slot = self.index(deadline);
+        self.slot[slot].push(Timer { id, deadline, action });
+        TimerHandle { id, deadline, slot }
+    }
+
+    fn cancel(&mut self, handle: TimerHandle) -> bool {
+        let slot = &mut self.slot[handle.slot];
+        match slot.iter().position(|timer| timer.id == handle.id) {
+            Some(i) => {
+                slot.swap_remove(i);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // take the timers that have expired by `now`
+    fn expire(&mut self, now: u64) -> Vec<Action> {
+        let mut expired = Vec::new();
+
+        // every slot has to be looked at once if we fell a whole round behind
+        let curr = now >> SLOT_SHIFT;
+        let first = self.last.max(curr.saturating_sub(WHEEL_SIZE as u64 - 1));
+        for n in first..=curr {
+            let slot = &mut self.slot[n as usize % WHEEL_SIZE];
+            let mut i = 0;
+            while i < slot.len() {
+                if slot[i].deadline <= now {
+                    expired.push(slot.swap_remove(i).action);
+                } else {
+                    i += 1;
+                }
+            }
+        }
+
+        // the current slot may still hold timers of this round.
+        // `now` may have been read before another cpu ran a later slot, `last` never goes back.
+        self.last = self.last.max(curr);
+        expired
+    }
+
+    fn next_deadline(&self) -> Option<u64> {
+        self.slot.iter().flatten().map(|timer| timer.deadline).min()
+    }
+}
+
+pub fn init() {
+    unsafe {
+        BOOT = now();
+    }
+}
+
+pub fn now() -> u64 {
+    let cnt: u64;
+    unsafe {
+        asm!("isb; mrs {}, CNTPCT_EL0", out(reg) cnt);
+    }
+    cnt
+}
+
+fn frequency() -> u64 {
+    let freq: u64;
+    unsafe {
+        asm!("mrs {}, CNTFRQ_EL0", out(reg) freq);
+    }
+    freq
+}
+
+pub fn from_nanos(ns: u64) -> u64 {
+    (ns as u128 * frequency() as u128 / NSEC_PER_SEC as u128) as u64
+}
+
+pub fn to_nanos(ticks: u64) -> u64 {
+    (ticks as u128 * NSEC_PER_SEC as u128 / frequency() as u128) as u64
+}
+
+pub fn from_secs(secs: u64) -> u64 {
+    secs.saturating_mul(frequency())
+}
+
+// rounded up, so an alarm that's about to go off still counts
+pub fn to_secs(ticks: u64) -> u64 {
+    let freq = frequency();
+    (ticks + freq - 1) / freq
+}
+
+pub fn add(deadline: u64, action: Action) -> TimerHandle {
+    let handle = WHEEL.lock().add(deadline, action);
+    // it may be earlier than what the hardware timer is set to
+    rearm();
+    handle
+}
+
+// returns false if it has already gone off
+pub fn cancel(handle: TimerHandle) -> bool {
+    WHEEL.lock().cancel(handle)
+}
+
+// fire the expired timers, any cpu may run them
+pub fn run() {
+    let expired = WHEEL.lock().expire(now());
+    for action in expired {
+        process::timer_expired(action);
+    }
+}
+
+// the scheduler gives the next process `ticks` to run
+pub fn start_slice(ticks: u64) {
+    unsafe {
+        SLICE_END[cpu_id()] = now() + ticks;
+    }
+    rearm();
+}
+
+// nothing is running, only the deadlines can wake up the cpu
+pub fn stop_slice() {
+    unsafe {
+        SLICE_END[cpu_id()] = u64::MAX;
+    }
+    rearm();
+}
+
+pub fn slice_expired() -> bool {
+    unsafe {
+        now() >= SLICE_END[cpu_id()]
+    }
+}
+
+// program the hardware timer for whichever comes first,
+// the end of the time slice or the next deadline.
+// every cpu has its own timer, they all watch the deadlines.
+pub fn rearm() {
+    // the interrupt handler may end the slice in between
+    let wheel = WHEEL.lock();
+    let slice_end = unsafe { SLICE_END[cpu_id()] };
+    let cval = wheel.next_deadline().map_or(slice_end, |deadline| deadline.min(slice_end));
+
+    unsafe {
+        asm!("msr CNTP_CVAL_EL0, {}", in(reg) cval);
+    }
+}
+
+pub fn account_idle(ticks: u64) {
+    unsafe {
+        IDLE[cpu_id()] += ticks;
+    }
+}
+
+pub fn uptime() -> u64 {
+    unsafe {
+        now() - BOOT
+    }
+}
+
+// summed over the cpus
+pub fn idle_time() -> u64 {
+    unsafe {
+        IDLE.iter().sum()
+    }
+}
@@ -0,0 +1,132 @@
+use alloc::vec::Vec;
+use core::mem::{size_of, MaybeUninit};
+use crate::errno::Errno::{self, EFAULT, ENAMETOOLONG};
+use crate::process::Process;
+
+// the system calls get user memory only through these.
+// the range is checked to be in user space, the pages are checked by the
+// fault handler the same way as for user space, whatever it refuses
+// goes to the fixup in __ex_table and ends up as EFAULT.
+
+// the longest path, with the nul
+pub const PATH_MAX: usize = 4096;
+// the most bytes of argv taken by exec, with the pointers
+pub const ARG_MAX: usize = 64 * 1024;
+
+extern "C" {
+    static __ex_table_start: ExceptionEntry;
+    static __ex_table_end: ExceptionEntry;
+
+    fn __copy_from_user(dst: *mut u8, src: *const u8, len: usize) -> usize;
+    fn __copy_to_user(dst: *mut u8, src: *const u8, len: usize) -> usize;
+    fn __strncpy_user(dst: *mut u8, src: *const u8, max: usize) -> isize;
+}
+
+#[repr(C)]
+struct ExceptionEntry {
+    insn: usize,
+    fixup: usize,
+}
+
+// where to go on if the instruction at `elr` faults on user memory
+pub fn fixup(elr: usize) -> Option<usize> {
+    let table = unsafe {
+        let start = core::ptr::addr_of!(__ex_table_start);
+        let end = core::ptr::addr_of!(__ex_table_end);
+        core::slice::from_raw_parts(start, end.offset_from(start) as usize)
+    };
+    table.iter().find(|entry| entry.insn == elr).map(|entry| entry.fixup)
+}
+
+// user space is the upper half, the kernel is in TTBR0_EL1
+pub fn access_ok(addr: usize, len: usize) -> bool {
+    match addr.checked_add(len) {
+        Some(end) => addr >= Process::USER_BASE_ADDR && end <= Process::USER_STACK_TOP,
+        None => false,
+    }
+}
+
+unsafe fn copy_in(dst: *mut u8, src: *const u8, len: usize) -> Result<(), Errno> {
+    match __copy_from_user(dst, src, len) {
+        0 => Ok(()),
+        _ => Err(EFAULT),
+    }
+}
+
+unsafe fn copy_out(dst: *mut u8, src: *const u8, len: usize) -> Result<(), Errno> {
+    match __copy_to_user(dst, src, len) {
+        0 => Ok(()),
+        _ => Err(EFAULT),
+    }
+}
+
+pub fn copy_from_user(dst: &mut [u8], src: usize) -> Result<(), Errno> {
+    if !access_ok(src, dst.len()) {
+        return Err(EFAULT);
+    }
+    unsafe {
+        copy_in(dst.as_mut_ptr(), src as *const u8, dst.len())
+    }
+}
+
+pub fn copy_to_user(dst: usize, src: &[u8]) -> Result<(), Errno> {
+    if !access_ok(dst, src.len()) {
+        return Err(EFAULT);
+    }
+    unsafe {
+        copy_out(dst as *mut u8, src.as_ptr(), src.len())
+    }
+}
+
+pub fn read_user<T: Copy>(src: *const T) -> Result<T, Errno> {
+    if !access_ok(src as usize, size_of::<T>()) {
+        return Err(EFAULT);
+    }
+    let mut val = MaybeUninit::<T>::uninit();
+    unsafe {
+        copy_in(val.as_mut_ptr() as *mut u8, src as *const u8, size_of::<T>())?;
+        Ok(val.assume_init())
+    }
+}
+
+pub fn write_user<T: Copy>(dst: *mut T, val: T) -> Result<(), Errno> {
+    if !access_ok(dst as usize, size_of::<T>()) {
+        return Err(EFAULT);
+    }
+    unsafe {
+        copy_out(dst as *mut u8, core::ptr::addr_of!(val) as *const u8, size_of::<T>())
+    }
+}
+
+// `len` bytes at `src`
+pub fn read_user_bytes(src: usize, len: usize) -> Result<Vec<u8>, Errno> {
+    let mut buf = alloc::vec![0_u8; len];
+    copy_from_user(&mut buf, src)?;
+    Ok(buf)
+}
+
+// a nul-terminated string of at most `max` bytes with the nul, which isn't returned
+pub fn read_user_str(src: usize, max: usize) -> Result<Vec<u8>, Errno> {
+    // it may end well before the end of user space
+    if !access_ok(src, 1) {
+        return Err(EFAULT);
+    }
+    let limit = max.min(Process::USER_STACK_TOP - src);
+
+    let mut buf = Vec::with_capacity(limit);
+    let len = unsafe {
+        __strncpy_user(buf.as_mut_ptr(), src as *const u8, limit)
+    };
+    match len {
+        -1 => Err(EFAULT),
+        // it runs off the end of user space
+        len if len as usize == limit && limit < max => Err(EFAULT),
+        len if len as usize == limit => Err(ENAMETOOLONG),
+        len => {
+            unsafe {
+                buf.set_len(len as usize);
+            }
+            Ok(buf)
+        }
+    }
+}
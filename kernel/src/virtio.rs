@@ -4,6 +4,9 @@ use alloc::vec;
 use core::mem;
 use crate::fs::buffer::Buffer;
 use crate::process;
+use crate::timer;
+use crate::workqueue;
+use spin::Mutex;
 
 macro_rules! reg {
     ($name:ident, $addr:expr) => {
@@ -53,8 +56,10 @@ const VIRTIO_STATUS_FEAT_OK:     u32 = 8;
 const VIRTIO_DEV_BLK:            u32 = 0x02;
 
 const NUM: u32 = 8;
+// in seconds
+const DISK_TIMEOUT: u64 = 5;
 
-static mut DISK: Disk = Disk::new();
+static DISK: Mutex<Disk> = Mutex::new(Disk::new());
 
 #[repr(packed)]
 struct VirtIO {
@@ -132,7 +137,7 @@ impl VirtIO {
         let addr = Box::into_raw(vec![0_u8; 8192].into_boxed_slice()) as *mut u8 as usize;
         self.write(QUEUE_PFN, (addr >> 12) as u32);
 
-        DISK.addr = addr;
+        DISK.lock().addr = addr;
 
         crate::gic::irq_enable(self.irq as u32);
     }
@@ -156,10 +161,10 @@ pub fn init() {
     }
 }
 
-unsafe fn alloc3_desc() -> Result<[usize; 3], ()> {
+fn alloc3_desc(disk: &mut Disk) -> Result<[usize; 3], ()> {
     let mut res = [0; 3];
     let mut idx = 0;
-    for (i, v) in DISK.free.iter_mut().enumerate() {
+    for (i, v) in disk.free.iter_mut().enumerate() {
         if *v {
             *v = false;
             res[idx] = i;
@@ -171,50 +176,57 @@ unsafe fn alloc3_desc() -> Result<[usize; 3], ()> {
     }
 
     if idx < 3 {
-        free_desc(res, idx);
+        free_desc(disk, res, idx);
         return Err(());
     }
 
     Ok(res)
 }
 
-unsafe fn free_desc(idx: [usize; 3], cnt: usize) {
+fn free_desc(disk: &mut Disk, idx: [usize; 3], cnt: usize) {
     for i in (0..cnt).map(|i| idx[i]) {
-        if DISK.free[i] {
+        if disk.free[i] {
             panic!("free desc");
         }
-        let desc = DISK.desc();
+        let desc = unsafe { disk.desc() };
         desc[i].addr = 0;
         desc[i].len = 0;
         desc[i].flags = 0;
         desc[i].next = 0;
-        DISK.free[i] = true;
-        process::wakeup(core::ptr::addr_of!(DISK.free) as usize);
+        disk.free[i] = true;
     }
+    process::wakeup(disk.free_channel());
 }
 
+// the disk lock is dropped while sleeping
 pub unsafe fn disk_rw(buffer: &mut Buffer, write: bool) {
     let sector = buffer.blockno() as usize * (crate::fs::BLOCK_SIZE / 512);
 
     // allocate descriptor
+    let mut disk = DISK.lock();
     let idx = loop {
-        match alloc3_desc() {
+        match alloc3_desc(&mut disk) {
             Ok(idx) => break idx,
-            Err(()) => process::sleep(core::ptr::addr_of!(DISK.free) as usize),
+            Err(()) => {
+                let channel = disk.free_channel();
+                process::sleep_on(channel, disk);
+                disk = DISK.lock();
+            }
         };
     };
 
-    let blk_req = &mut DISK.ops[idx[0]];
+    let blk_req = &mut disk.ops[idx[0]];
     blk_req.ty = match write {
         true  => VIRTIO_BLK_T_OUT,
         false => VIRTIO_BLK_T_IN,
     };
     blk_req.reserved = 0;
     blk_req.sector = sector as u64;
+    let blk_req = core::ptr::addr_of!(*blk_req) as u64;
 
-    let desc = DISK.desc();
+    let desc = disk.desc();
 
-    desc[idx[0]].addr = core::ptr::addr_of!(*blk_req) as u64;
+    desc[idx[0]].addr = blk_req;
     desc[idx[0]].len = mem::size_of::<VirtioBlkReq>() as u32;
     desc[idx[0]].flags = VRING_DESC_F_NEXT;
     desc[idx[0]].next = idx[1] as u16;
@@ -228,28 +240,37 @@ pub unsafe fn disk_rw(buffer: &mut Buffer, write: bool) {
     } | VRING_DESC_F_NEXT;
     desc[idx[1]].next = idx[2] as u16;
 
-    DISK.info[idx[0]].status = 0xff;
-    desc[idx[2]].addr = core::ptr::addr_of!(DISK.info[idx[0]].status) as u64;
+    disk.info[idx[0]].status = 0xff;
+    disk.info[idx[0]].done = false;
+    desc[idx[2]].addr = core::ptr::addr_of!(disk.info[idx[0]].status) as u64;
     desc[idx[2]].len = 1;
     desc[idx[2]].flags = VRING_DESC_F_WRITE;
     desc[idx[2]].next = 0;
 
-    DISK.info[idx[0]].buf = core::ptr::addr_of_mut!(*buffer);
-    buffer.busy = true;
+    disk.info[idx[0]].buf = core::ptr::addr_of_mut!(*buffer);
 
-    DISK.avail().ring[DISK.avail().idx as usize % NUM as usize] = idx[0] as u16;
+    let avail = disk.avail();
+    avail.ring[avail.idx as usize % NUM as usize] = idx[0] as u16;
     mb!();
-    DISK.avail().idx += 1;
+    avail.idx += 1;
     mb!();
     VirtIO::new(0).write(QUEUE_NOTIFY, 0);
     mb!();
 
-    while (*DISK.info[idx[0]].buf).busy {
-        process::sleep((*DISK.info[idx[0]].buf).as_ptr() as usize);
+    // the device doesn't answer, complain and keep waiting
+    let channel = buffer.as_ptr() as usize;
+    let mut deadline = timer::now() + timer::from_secs(DISK_TIMEOUT);
+    while !disk.info[idx[0]].done {
+        let timed_out = process::sleep_timeout_on(channel, deadline, disk);
+        disk = DISK.lock();
+        if timed_out && !disk.info[idx[0]].done {
+            println!("virtio: request for sector {} hung", sector);
+            deadline = timer::now() + timer::from_secs(DISK_TIMEOUT);
+        }
     }
 
-    DISK.info[idx[0]].buf = core::ptr::null_mut();
-    free_desc(idx, 3);
+    disk.info[idx[0]].buf = core::ptr::null_mut();
+    free_desc(&mut disk, idx, 3);
 }
 
 pub unsafe fn interrupt_handler() {
@@ -257,18 +278,25 @@ pub unsafe fn interrupt_handler() {
     virtio.write(INTERRUPT_ACK, virtio.read(INTERRUPT_STATUS) & 0x03);
     mb!();
 
-    while DISK.used_idx != DISK.used().idx {
+    // the used ring is looked at by the workqueue
+    workqueue::schedule(|| unsafe { complete() });
+}
+
+// wake up the requests the device has finished
+unsafe fn complete() {
+    let mut disk = DISK.lock();
+    while disk.used_idx != disk.used().idx {
         mb!();
-        let id = DISK.used().ring[DISK.used_idx as usize % NUM as usize].id as usize;
+        let id = disk.used().ring[disk.used_idx as usize % NUM as usize].id as usize;
 
-        if DISK.info[id].status != 0 {
-            panic!("virtio disk intr status {}", DISK.info[id].status);
+        if disk.info[id].status != 0 {
+            panic!("virtio disk intr status {}", disk.info[id].status);
         }
 
-        (*DISK.info[id].buf).busy = false;
-        process::wakeup((*DISK.info[id].buf).as_ptr() as usize);
+        disk.info[id].done = true;
+        process::wakeup((*disk.info[id].buf).as_ptr() as usize);
 
-        DISK.used_idx += 1;
+        disk.used_idx += 1;
     }
 }
 
@@ -280,9 +308,13 @@ struct Disk {
     info: [Info; NUM as usize]
 }
 
+// the buffers are only touched by the request that owns them
+unsafe impl Send for Disk {}
+
 #[derive(Clone, Copy)]
 struct Info {
     status: u8,
+    done: bool,
     buf: *mut Buffer,
 }
 
@@ -290,6 +322,7 @@ impl Info {
     const fn new() -> Self {
         Self {
             status: 0,
+            done: false,
             buf: core::ptr::null_mut(),
         }
     }
@@ -306,15 +339,21 @@ impl Disk {
         }
     }
 
-    unsafe fn desc(&mut self) -> &mut [VirtqDesc] {
+    // the processes waiting for free descriptors sleep on it
+    fn free_channel(&self) -> usize {
+        self.free.as_ptr() as usize
+    }
+
+    // the rings live in the memory allocated in `init`, which is never freed
+    unsafe fn desc(&self) -> &'static mut [VirtqDesc] {
         &mut *(self.addr as *mut VirtqDesc as *mut [VirtqDesc; NUM as usize])
     }
 
-    unsafe fn avail(&mut self) -> &mut VirtqAvail {
+    unsafe fn avail(&self) -> &'static mut VirtqAvail {
         &mut *((self.addr + NUM as usize * mem::size_of::<VirtqDesc>()) as *mut VirtqAvail)
     }
 
-    unsafe fn used(&mut self) -> &mut VirtqUsed {
+    unsafe fn used(&self) -> &'static mut VirtqUsed {
         &mut *((self. addr + 0x1000) as *mut VirtqUsed)
     }
 }
@@ -0,0 +1,91 @@
+use spin::Mutex;
+use super::{flush_tlb, flush_tlb_local};
+use crate::smp::{cpu_id, NCPU};
+
+// TCR_EL1.AS is 0, so we have 8 bits ASID
+const ASID_BITS: usize = 8;
+const ASID_MASK: usize = (1 << ASID_BITS) - 1;
+// ASID 0 is used by the kernel
+const ASID_FIRST: usize = 1;
+
+static ALLOCATOR: Mutex<Allocator> = Mutex::new(Allocator::new());
+
+// the upper bits of an allocated ASID is the generation it belongs to
+struct Allocator {
+    generation: usize,
+    next: usize,
+    // the ASID running on each cpu
+    active: [usize; NCPU],
+    // ASIDs still running when the generation rolled over, they are kept
+    reserved: [usize; NCPU],
+    // the cpu hasn't dropped the translations of the old generation
+    flush_pending: [bool; NCPU],
+}
+
+impl Allocator {
+    const fn new() -> Self {
+        Self {
+            generation: 1 << ASID_BITS,
+            next: ASID_FIRST,
+            active: [0; NCPU],
+            reserved: [0; NCPU],
+            flush_pending: [false; NCPU],
+        }
+    }
+
+    fn is_reserved(&self, asid: usize) -> bool {
+        self.reserved.iter().any(|r| *r != 0 && *r & ASID_MASK == asid & ASID_MASK)
+    }
+
+    fn alloc(&mut self) -> usize {
+        loop {
+            if self.next > ASID_MASK {
+                // run out of ASID, start a new generation.
+                // ASIDs of the old generation will be reallocated,
+                // the translations tagged with them have to go.
+                self.generation += 1 << ASID_BITS;
+                self.next = ASID_FIRST;
+                self.reserved = self.active;
+                self.flush_pending = [true; NCPU];
+                flush_tlb();
+            }
+
+            let asid = self.next;
+            self.next += 1;
+            if !self.is_reserved(asid) {
+                return self.generation | asid;
+            }
+        }
+    }
+
+    fn activate(&mut self, asid: usize) -> usize {
+        let cpu = cpu_id();
+        if self.flush_pending[cpu] {
+            flush_tlb_local();
+            self.flush_pending[cpu] = false;
+        }
+        self.active[cpu] = asid;
+        asid & ASID_MASK
+    }
+}
+
+// make sure `asid` belongs to the current generation,
+// returns the value for the ASID field of TTBR1_EL1
+pub fn check(asid: &mut usize) -> usize {
+    let mut allocator = ALLOCATOR.lock();
+    if *asid & !ASID_MASK != allocator.generation {
+        *asid = match *asid != 0 && allocator.reserved.contains(asid) {
+            // it was running during the rollover, it keeps the number
+            true => allocator.generation | (*asid & ASID_MASK),
+            false => allocator.alloc(),
+        };
+    }
+    allocator.activate(*asid)
+}
+
+// the translations of the old address space may still be in the TLB
+pub fn renew(asid: &mut usize) -> usize {
+    let mut allocator = ALLOCATOR.lock();
+    *asid = allocator.alloc();
+    allocator.activate(*asid)
+}
@@ -1,21 +1,15 @@
+pub mod asid;
+
 use crate::common::*;
+use crate::mm::frame;
+use crate::errno::Errno::{self, EFAULT, ENOMEM};
 use core::{alloc::{Layout, GlobalAlloc}, ops::{Index, IndexMut}};
 
+static mut KERNEL_TT: usize = 0;
+
 pub fn init(kernel_tt: usize, kernel_text_end: usize) {
-    // initialize virtual memory translation
     unsafe {
-        // Outer-sharable
-        // Normal memory, Outer Write-Back Read-Allocate Write-Allocate Cacheable
-        // Normal memory, Inner Write-Back Read-Allocate Write-Allocate Cacheable
-        // 48 bits address space for TTBR0_EL1
-        // 48 bits address space for TTBR1_EL1
-        let x = 0x5a5102410_usize;
-        asm!("msr TCR_EL1, {}", in(reg) x);
-
-        // Attr1: Normal
-        // Attr0: Device_nGnRnE
-        let x = 0xff00_usize;
-        asm!("msr MAIR_EL1, {}", in(reg) x);
+        KERNEL_TT = kernel_tt;
     }
 
     let mut pgt = PageTable::from(kernel_tt);
@@ -40,11 +34,45 @@ pub fn init(kernel_tt: usize, kernel_text_end: usize) {
             PHYEND - kernel_text_end,
             PageTableKind::Kernel, "rw");
 
+    init_cpu();
+}
+
+// a cpu coming up reads KERNEL_TT and walks the kernel table with its caches off,
+// so they have to be in memory and not only in the caches of this one
+pub fn clean_kernel_table() {
+    unsafe {
+        crate::smp::clean_dcache(core::ptr::addr_of!(KERNEL_TT) as usize, core::mem::size_of::<usize>());
+        PageTable::from(KERNEL_TT).clean(0);
+    }
+}
+
+// every cpu turns on its own MMU with the kernel table
+pub fn init_cpu() {
+    let kernel_tt = unsafe { KERNEL_TT };
+
+    // initialize virtual memory translation
+    unsafe {
+        // Outer-sharable
+        // Normal memory, Outer Write-Back Read-Allocate Write-Allocate Cacheable
+        // Normal memory, Inner Write-Back Read-Allocate Write-Allocate Cacheable
+        // 48 bits address space for TTBR0_EL1
+        // 48 bits address space for TTBR1_EL1
+        // ASID is defined by TTBR1_EL1
+        let x = 0x5a5502410_usize;
+        asm!("msr TCR_EL1, {}", in(reg) x);
+
+        // Attr1: Normal
+        // Attr0: Device_nGnRnE
+        let x = 0xff00_usize;
+        asm!("msr MAIR_EL1, {}", in(reg) x);
+    }
+
     unsafe {
         let mut x: usize;
         asm!("mrs {}, SCTLR_EL1", out(reg) x);
         x |= 1;
-        asm!("msr TTBR1_EL1, {0}",
+        asm!("TLBI VMALLE1",
+             "msr TTBR1_EL1, {0}",
              "msr TTBR0_EL1, {0}",
              "msr SCTLR_EL1, {1}",
              "DSB SY",
@@ -70,6 +98,9 @@ const KERNEL_TABLE:    usize = 0b01 << 61;
 const UXN:             usize = 1 << 54;  // Unprivilege execute never
 const PXN:             usize = 1 << 53;  // Privilege   execute never
 const _CONTIGIOUS:     usize = 1 << 52;  // Contiguous bit
+// [58:55] => reserved for software use
+const COW:             usize = 1 << 55;  // Copy-on-write
+const SHARED:          usize = 1 << 56;  // of a shared mapping, never copy-on-write
 
 // Lower attribute
 const NG:              usize = 1 << 11;  // non-Global
@@ -88,6 +119,17 @@ const ENTRY_TABLE:     usize = 1 << 1;
 const ENTRY_PAGE:      usize = 1 << 1;
 const VALID:           usize = 1 << 0;
 
+// (read-only, executable)
+fn parse_perm(perm: &str) -> (bool, bool) {
+    match perm {
+        "rwx" => (false, true),
+        "rw" => (false, false),
+        "rx" => (true, true),
+        "r"  => (true, false),
+        _ => panic!("???")
+    }
+}
+
 impl PageTableEntry {
     const PHYSICAL_ADDRESS_BITS: usize = 0xffff_ffff_f000;
 
@@ -140,6 +182,46 @@ impl PageTableEntry {
         self.data & VALID != 0
     }
 
+    fn is_user(&self) -> bool {
+        self.data & NG != 0
+    }
+
+    fn is_cow(&self) -> bool {
+        self.data & COW != 0
+    }
+
+    // a user page, it stays copy-on-write as long as it's shared.
+    // a page of a shared mapping only becomes writable on a write fault,
+    // so the write can be noted.
+    fn set_perm(&mut self, perm: &str) {
+        let (mut ro, x) = match perm {
+            "" => (true, false),
+            perm => parse_perm(perm),
+        };
+        let cow = self.data & SHARED == 0 && frame::get_ref(self.as_addr().unwrap()) > 1;
+        if self.data & SHARED != 0 && self.data & AP_RO != 0 {
+            ro = true;
+        }
+
+        self.data &= !(AP_RO | AP_UA | UXN | PXN | COW);
+        self.data |= if x { PXN } else { UXN | PXN };
+        if !perm.is_empty() {
+            self.data |= AP_UA;
+        }
+        if ro {
+            self.data |= AP_RO;
+        } else if cow {
+            self.data |= AP_RO | COW;
+        }
+    }
+
+    // writable pages become read-only until someone writes to it
+    fn mark_cow(&mut self) {
+        if self.data & (AP_RO | SHARED) == 0 {
+            self.data |= AP_RO | COW;
+        }
+    }
+
     // fn is_page_or_block(&self, level: u8) -> bool {
     //     self.is_valid() && 
     //     ((level < 3 && self.data & ENTRY_TABLE == 0) || level == 3)
@@ -168,8 +250,15 @@ impl PageTableEntry {
                 _ => panic!("??"),
             };
 
+            let pa = self.as_addr().unwrap();
+            // user page may be shared with other processes
+            if self.is_user() && !self.is_table(level) && frame::dec_ref(pa) > 0 {
+                self.data = 0;
+                return;
+            }
+
             unsafe {
-                crate::ALLOCATOR.dealloc(self.as_addr().unwrap() as *mut u8, Layout::from_size_align_unchecked(size, 4));
+                crate::ALLOCATOR.dealloc(pa as *mut u8, Layout::from_size_align_unchecked(size, 4));
             }
             self.data = 0;
         }
@@ -268,7 +357,10 @@ impl PageTable {
         pa = round_down(pa);
         let end = round_down(va + len - 1);
         while curr <= end {
-            let block_size = if curr & (BLOCK_1GB - 1) == 0 && len >= BLOCK_1GB {
+            // user pages are always 4KB so that they can be shared page by page
+            let block_size = if kind == PageTableKind::User {
+                BLOCK_4KB
+            } else if curr & (BLOCK_1GB - 1) == 0 && len >= BLOCK_1GB {
                 BLOCK_1GB
             } else if curr & (BLOCK_2MB - 1) == 0 && len >= BLOCK_2MB {
                 BLOCK_2MB
@@ -287,13 +379,7 @@ impl PageTable {
         if level == 3 || (level == 2 && block == BLOCK_2MB) ||
                          (level == 1 && block == BLOCK_1GB) 
         {
-            let (ro, x) = match perm {
-                "rwx" => (false, true),
-                "rw" => (false, false),
-                "rx" => (true, true),
-                "r"  => (true, false),
-                _ => panic!("???")
-            };
+            let (ro, x) = parse_perm(perm);
             self[(va, level)].new_block(pa, kind, ro, x, block);
             return;    
         }
@@ -311,7 +397,7 @@ impl PageTable {
         PageTable::from(addr).install(va, pa, kind, perm, level + 1, block);
     }
 
-    pub fn create(&mut self, va: usize, len: usize, perm: &str) -> Result<usize, isize> {
+    pub fn create(&mut self, va: usize, len: usize, perm: &str) -> Result<usize, Errno> {
         assert_eq!(va & 0xfff, 0);
         assert_eq!(len & 0xfff, 0);
         let ptr = unsafe {
@@ -319,13 +405,171 @@ impl PageTable {
         };
 
         if ptr.is_null() {
-            return Err(-1);
+            return Err(ENOMEM);
+        }
+
+        for pa in (ptr as usize..ptr as usize + len).step_by(PAGESIZE) {
+            frame::init_ref(pa);
         }
 
         self.map(va, ptr as usize, len, PageTableKind::User, perm);
         Ok(ptr as usize)
     }
 
+    // share every user page with `child`, writable pages become copy-on-write.
+    // if it runs out of memory, what has been shared is given back and `child` is empty again.
+    // ours stay copy-on-write, the first write finds they aren't shared anymore.
+    pub fn share_with(&mut self, child: &mut PageTable) -> Result<(), Errno> {
+        self.share_inner(child, 0).map_err(|err| {
+            child.release_inner(0);
+            err
+        })
+    }
+
+    fn share_inner(&mut self, child: &mut PageTable, level: u8) -> Result<(), Errno> {
+        for (entry, child_entry) in self.entrys.iter_mut().zip(child.entrys.iter_mut()) {
+            if !entry.is_valid() {
+                continue;
+            }
+
+            if entry.is_table(level) {
+                let addr = unsafe {
+                    crate::ALLOCATOR.alloc(Layout::from_size_align_unchecked(PAGESIZE, 4))
+                } as usize;
+
+                if addr == 0 {
+                    return Err(ENOMEM);
+                }
+
+                child_entry.new_table(addr, PageTableKind::User);
+                PageTable::from(entry.as_addr().unwrap())
+                            .share_inner(&mut PageTable::from(addr), level + 1)?;
+            } else {
+                entry.mark_cow();
+                frame::inc_ref(entry.as_addr().unwrap());
+                child_entry.data = entry.data;
+            }
+        }
+        Ok(())
+    }
+
+    // resolve a write to a copy-on-write page
+    pub fn copy_on_write(&mut self, va: usize) -> Result<(), Errno> {
+        let entry = self.walk(va).ok_or(EFAULT)?;
+        if !entry.is_cow() {
+            return Err(EFAULT);
+        }
+
+        let pa = entry.as_addr().unwrap();
+        if frame::get_ref(pa) > 1 {
+            let new = unsafe {
+                crate::ALLOCATOR.alloc(Layout::from_size_align_unchecked(PAGESIZE, 4))
+            };
+
+            if new.is_null() {
+                return Err(ENOMEM);
+            }
+
+            unsafe {
+                core::ptr::copy_nonoverlapping(pa as *const u8, new, PAGESIZE);
+            }
+
+            // the others may have copied it meanwhile
+            if frame::dec_ref(pa) == 0 {
+                unsafe {
+                    crate::ALLOCATOR.dealloc(pa as *mut u8, Layout::from_size_align_unchecked(PAGESIZE, 4));
+                }
+            }
+            frame::init_ref(new as usize);
+            entry.data = (entry.data & !PageTableEntry::PHYSICAL_ADDRESS_BITS) | new as usize;
+        }
+
+        // we are the only one
+        entry.data &= !(AP_RO | COW);
+        flush_tlb();
+        Ok(())
+    }
+
+    // map a frame someone else holds too, e.g. of the page cache.
+    // unless it's `shared`, it's copied on the first write.
+    pub fn map_page(&mut self, va: usize, pa: usize, perm: &str, shared: bool) {
+        frame::inc_ref(pa);
+        self.map(va, pa, PAGESIZE, PageTableKind::User, perm);
+        let entry = self.walk(va).unwrap();
+        match shared {
+            true  => entry.data |= SHARED,
+            false => entry.mark_cow(),
+        }
+    }
+
+    // the first write to a page of a shared mapping
+    pub fn make_writable(&mut self, va: usize) {
+        if let Some(entry) = self.walk(va) {
+            entry.data &= !AP_RO;
+            flush_tlb();
+        }
+    }
+
+    // the pages in the range go away, the tables stay
+    pub fn unmap(&mut self, va: usize, len: usize) {
+        for va in (va..va + len).step_by(PAGESIZE) {
+            if let Some(entry) = self.walk(va) {
+                entry.invalidate(3);
+            }
+        }
+        flush_tlb();
+    }
+
+    // the pages in the range that are there get `perm`, "" means no access from EL0
+    pub fn protect(&mut self, va: usize, len: usize, perm: &str) {
+        for va in (va..va + len).step_by(PAGESIZE) {
+            if let Some(entry) = self.walk(va) {
+                entry.set_perm(perm);
+            }
+        }
+        flush_tlb();
+    }
+
+    // the physical address `va` is mapped to
+    pub fn translate(&mut self, va: usize) -> Option<usize> {
+        Some(self.walk(va)?.as_addr()? | (va & 0xfff))
+    }
+
+    // whether an access to `va` would go through now
+    pub fn is_accessible(&mut self, va: usize, write: bool) -> bool {
+        match self.walk(va) {
+            Some(entry) => entry.data & AP_UA != 0 && (!write || entry.data & (AP_RO | COW) == 0),
+            None => false,
+        }
+    }
+
+    // find the page entry of `va`
+    fn walk(&mut self, va: usize) -> Option<&'static mut PageTableEntry> {
+        let mut table = PageTable::from(self.as_ptr());
+        for level in 0..3 {
+            if !table[(va, level)].is_table(level) {
+                return None;
+            }
+            table = PageTable::from(table[(va, level)].as_addr()?);
+        }
+
+        let entry = &mut table.entrys[(va >> PAGESHIFT) & 0x1ff];
+        match entry.is_valid() {
+            true  => Some(entry),
+            false => None,
+        }
+    }
+
+    // to the point of coherency, with the tables below it
+    fn clean(&self, level: u8) {
+        crate::smp::clean_dcache(self.entrys.as_ptr() as usize, PAGESIZE);
+        for entry in self.entrys.iter() {
+            if entry.is_table(level) {
+                PageTable::from(entry.as_addr().unwrap()).clean(level + 1);
+            }
+        }
+    }
+
     pub fn release(&mut self) {
         self.release_inner(0);
         let ptr = self.entrys.as_ptr() as *mut u8;
@@ -345,6 +589,38 @@ impl PageTable {
     }
 }
 
+// the value of TTBR1_EL1 when there is no user space
+pub fn kernel_table() -> usize {
+    unsafe {
+        KERNEL_TT
+    }
+}
+
+// stop using the page table of user process
+pub fn load_kernel_table() {
+    unsafe {
+        asm!("msr ttbr1_el1, {}", in(reg) KERNEL_TT);
+    }
+    flush_tlb();
+}
+
+// on every cpu, the process may have run on any of them
+pub fn flush_tlb() {
+    unsafe {
+        asm!("TLBI VMALLE1IS",
+             "dsb ish",
+             "isb sy");
+    }
+}
+
+pub fn flush_tlb_local() {
+    unsafe {
+        asm!("TLBI VMALLE1",
+             "dsb nsh",
+             "isb sy");
+    }
+}
+
 impl Index<(usize, u8)> for PageTable {
     type Output = PageTableEntry;
     fn index(&self, (va, level): (usize, u8)) -> &Self::Output {
@@ -0,0 +1,53 @@
+use alloc::boxed::Box;
+use alloc::collections::vec_deque::VecDeque;
+use core::mem::MaybeUninit;
+use crate::irq::{IrqMutex, IrqMutexGuard};
+use crate::process;
+
+// work deferred by the interrupt handlers, run by a kernel thread.
+// there is only one worker, so the work runs in the order it was queued.
+// the work may sleep, but not on something queued after it.
+type Work = Box<dyn FnOnce() + Send>;
+
+static mut QUEUE: MaybeUninit<IrqMutex<VecDeque<Work>>> = MaybeUninit::uninit();
+
+pub fn init() {
+    unsafe {
+        QUEUE = MaybeUninit::new(IrqMutex::new(VecDeque::new()));
+    }
+    process::spawn_kthread(worker);
+}
+
+fn queue() -> IrqMutexGuard<'static, VecDeque<Work>> {
+    unsafe {
+        QUEUE.assume_init_ref().lock()
+    }
+}
+
+// the worker sleeps on it when there is nothing to do
+fn channel() -> usize {
+    unsafe {
+        QUEUE.as_ptr() as usize
+    }
+}
+
+// safe to call in interrupt context
+pub fn schedule(work: impl FnOnce() + Send + 'static) {
+    let mut queue = queue();
+    queue.push_back(Box::new(work));
+    process::wakeup(channel());
+}
+
+fn worker() {
+    loop {
+        let mut queue = queue();
+        match queue.pop_front() {
+            Some(work) => {
+                drop(queue);
+                work();
+                process::cond_resched();
+            }
+            None => process::sleep_on(channel(), queue),
+        }
+    }
+}
@@ -0,0 +1,51 @@
+#include "libc.h"
+
+static void segv(void)
+{
+    *(volatile int *)0 = 1;
+}
+
+static void bus(void)
+{
+    // exclusive loads are always checked for alignment
+    static long word[2];
+    asm volatile("ldxr x0, [%0]" :: "r"((char *)word + 1) : "x0", "memory");
+}
+
+static void ill(void)
+{
+    asm volatile("udf #0");
+}
+
+static void trap(void)
+{
+    asm volatile("brk #0");
+}
+
+static struct {
+    const char *name;
+    void (*fn)(void);
+} faults[] = {
+    { "segv", segv },
+    { "bus",  bus  },
+    { "ill",  ill  },
+    { "trap", trap },
+};
+
+int main(int argc, char *argv[])
+{
+    // every fault kills only the child that made it
+    for (int i = 0; i < sizeof(faults) / sizeof(faults[0]); i++) {
+        int pid = fork();
+        if (pid == 0) {
+            faults[i].fn();
+            printf("%s: survived\n", faults[i].name);
+            exit(0);
+        }
+
+        int wstatus;
+        waitpid(pid, &wstatus, 0);
+        printf("%s: killed by signal %d\n", faults[i].name, wstatus & 0x7f);
+    }
+    return 0;
+}
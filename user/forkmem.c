@@ -0,0 +1,52 @@
+#include "libc.h"
+
+#define CHILDREN 32
+// a child copies the stack page it writes to, and maybe the one above it
+#define PAGES_PER_CHILD 2
+
+// the children share our pages, they only copy the ones they write to
+int main(int argc, char *argv[])
+{
+    int pipefd[2];
+    if (pipe(pipefd) == -1) {
+        printf("pipe failed, errno %d\n", errno);
+        return -1;
+    }
+
+    int before = frames();
+    for (int i = 0; i < CHILDREN; i++) {
+        int pid = fork();
+        if (pid == -1) {
+            printf("fork failed, errno %d\n", errno);
+            return 1;
+        }
+        if (pid == 0) {
+            // wait until the parent has counted
+            char c;
+            close(pipefd[1]);
+            read(pipefd[0], &c, 1);
+            exit(0);
+        }
+    }
+
+    int during = frames();
+    close(pipefd[1]);
+    for (int i = 0; i < CHILDREN; i++) {
+        waitpid(-1, NULL, 0);
+    }
+    int after = frames();
+
+    printf("frames: %d before, %d with %d children, %d after\n", before, during, CHILDREN, after);
+    // a child has a copy of its stack and little else
+    if (during - before > CHILDREN * PAGES_PER_CHILD) {
+        printf("forkmem: %d frames per child, expected at most %d\n", (during - before) / CHILDREN, PAGES_PER_CHILD);
+        return 1;
+    }
+    // every shared page got its count back
+    if (after != before) {
+        printf("forkmem: %d frames not given back\n", after - before);
+        return 1;
+    }
+    printf("forkmem: ok\n");
+    return 0;
+}
@@ -1,42 +1,255 @@
 #include "libc.h"
 #include<stdarg.h>
 
+// shared by the threads of a process, there is no thread-local storage yet
+int errno;
+
+// set up by __start in crt.S
+char **environ;
+
+// the result of the system call is left in x0 for the caller,
+// __syscall_ret in crt.S turns an error into -1 and sets errno
+#define SYSCALL(num) asm volatile("svc " num "\n\tbl __syscall_ret" ::: "x1", "x2", "x30", "memory")
+
 int fork()
 {
-    asm("svc " SYS_FORK);
+    SYSCALL(SYS_FORK);
+}
+
+int execve(const char *pathname, char *const argv[], char *const envp[])
+{
+    SYSCALL(SYS_EXEC);
 }
 
 int exec(const char *pathname, char *const argv[])
 {
-    asm("svc " SYS_EXEC);
+    return execve(pathname, argv, environ);
 }
 
 int open(const char *pathname, int flags) {
-    asm("svc " SYS_OPEN);
+    SYSCALL(SYS_OPEN);
+}
+
+int close(int fd)
+{
+    SYSCALL(SYS_CLOSE);
 }
 
 int write(int fd, const void *buf, int count)
 {
-    asm("svc " SYS_WRITE);
+    SYSCALL(SYS_WRITE);
 }
 
 int read(int fd, void *buf, int count)
 {
-    asm("svc " SYS_READ);
+    SYSCALL(SYS_READ);
+}
+
+int waitpid(int pid, int *wstatus, int options)
+{
+    SYSCALL(SYS_WAITPID);
 }
 
-int waitpid(int pid, int *wstatus)
+void exit(int status)
 {
-    asm("svc " SYS_WAITPID);
+    asm("svc " SYS_EXIT);
 }
 
 int mkdir(char *path)
 {
-    asm("svc " SYS_MKDIR);
+    SYSCALL(SYS_MKDIR);
 }
 
 int chdir(char *path) {
-    asm("svc " SYS_CHDIR);
+    SYSCALL(SYS_CHDIR);
+}
+
+int dup(int oldfd)
+{
+    SYSCALL(SYS_DUP);
+}
+
+int dup2(int oldfd, int newfd)
+{
+    SYSCALL(SYS_DUP2);
+}
+
+int dup3(int oldfd, int newfd, int flags)
+{
+    SYSCALL(SYS_DUP3);
+}
+
+int fcntl(int fd, int cmd, int arg)
+{
+    SYSCALL(SYS_FCNTL);
+}
+
+int pipe(int pipefd[2])
+{
+    return pipe2(pipefd, 0);
+}
+
+int pipe2(int pipefd[2], int flags)
+{
+    SYSCALL(SYS_PIPE);
+}
+
+int kill(int pid, int sig)
+{
+    SYSCALL(SYS_KILL);
+}
+
+static int __sigaction(int signum, const struct sigaction *act, struct sigaction *oldact)
+{
+    SYSCALL(SYS_SIGACTION);
+}
+
+// defined in crt.S
+void __sigreturn(void);
+
+int sigaction(int signum, const struct sigaction *act, struct sigaction *oldact)
+{
+    struct sigaction kact;
+    if (act != NULL) {
+        // the handler returns to __sigreturn
+        kact = *act;
+        kact.sa_restorer = __sigreturn;
+        act = &kact;
+    }
+    return __sigaction(signum, act, oldact);
+}
+
+sighandler_t signal(int signum, sighandler_t handler)
+{
+    struct sigaction act = {0}, oldact;
+    act.sa_handler = handler;
+    if (sigaction(signum, &act, &oldact) == -1) {
+        return (sighandler_t)-1;
+    }
+    return oldact.sa_handler;
+}
+
+int sigprocmask(int how, const sigset_t *set, sigset_t *oldset)
+{
+    SYSCALL(SYS_SIGPROCMASK);
+}
+
+int getpid()
+{
+    SYSCALL(SYS_GETPID);
+}
+
+int tcsetpgrp(int fd, int pid)
+{
+    SYSCALL(SYS_TCSETPGRP);
+}
+
+int nice(int inc)
+{
+    SYSCALL(SYS_NICE);
+}
+
+static int __getpriority(int which, int who)
+{
+    SYSCALL(SYS_GETPRIORITY);
+}
+
+// the kernel returns 20 - nice so it's never negative
+int getpriority(int which, int who)
+{
+    int ret = __getpriority(which, who);
+    if (ret < 0)
+        return ret;
+    return 20 - ret;
+}
+
+int setpriority(int which, int who, int prio)
+{
+    SYSCALL(SYS_SETPRIORITY);
+}
+
+int sched_setscheduler(int pid, int policy, const struct sched_param *param)
+{
+    SYSCALL(SYS_SCHED_SETSCHEDULER);
+}
+
+int sched_getscheduler(int pid)
+{
+    SYSCALL(SYS_SCHED_GETSCHEDULER);
+}
+
+int nanosleep(const struct timespec *req, struct timespec *rem)
+{
+    SYSCALL(SYS_NANOSLEEP);
+}
+
+int clock_nanosleep(int clockid, int flags, const struct timespec *req, struct timespec *rem)
+{
+    SYSCALL(SYS_CLOCK_NANOSLEEP);
+}
+
+unsigned int alarm(unsigned int seconds)
+{
+    SYSCALL(SYS_ALARM);
+}
+
+int uptime(struct timespec *uptime, struct timespec *idle)
+{
+    SYSCALL(SYS_UPTIME);
+}
+
+int frames()
+{
+    SYSCALL(SYS_FRAMES);
+}
+
+// clone is in crt.S, the new thread starts on its own stack
+
+int gettid()
+{
+    SYSCALL(SYS_GETTID);
+}
+
+void exit_thread(int status)
+{
+    asm("svc " SYS_EXIT_THREAD);
+}
+
+int futex(int *uaddr, int op, int val, const struct timespec *timeout, int *uaddr2)
+{
+    SYSCALL(SYS_FUTEX);
+}
+
+void *mmap(void *addr, size_t length, int prot, int flags, int fd, long offset)
+{
+    SYSCALL(SYS_MMAP);
+}
+
+int munmap(void *addr, size_t length)
+{
+    SYSCALL(SYS_MUNMAP);
+}
+
+int mprotect(void *addr, size_t length, int prot)
+{
+    SYSCALL(SYS_MPROTECT);
+}
+
+// set by clone with CLONE_SETTLS
+void *get_tls(void)
+{
+    void *tls;
+    asm("mrs %0, tpidr_el0" : "=r"(tls));
+    return tls;
+}
+
+// returns the seconds left if it's interrupted
+unsigned int sleep(unsigned int seconds)
+{
+    struct timespec req = { seconds, 0 }, rem;
+    if (nanosleep(&req, &rem) < 0)
+        return rem.tv_sec + (rem.tv_nsec > 0);
+    return 0;
 }
 
 char *fgets(char *s, int size, int fd)
@@ -136,14 +349,22 @@ struct dirent *readdir(DIR *stream) {
 }
 
 int getdents(unsigned int fd, struct dirent *dirp, unsigned int count) {
-    asm("svc " SYS_GETDENTS);
+    SYSCALL(SYS_GETDENTS);
 }
 
 void *sbrk(size_t inc) {
-    asm("svc " SYS_SBRK);
+    SYSCALL(SYS_SBRK);
+}
+
+static char *__getcwd(char *buffer, size_t size)
+{
+    SYSCALL(SYS_GETCWD);
 }
 
 char *getcwd(char *buffer, size_t size)
 {
-    asm("svc " SYS_GETCWD);
+    char *ret = __getcwd(buffer, size);
+    if (ret == (char *)-1)
+        return NULL;
+    return ret;
 }
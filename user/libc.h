@@ -11,6 +11,61 @@
 #define SYS_GETCWD   "0x0A"
 #define SYS_MKDIR    "0x0B"
 #define SYS_CHDIR    "0x0C"
+#define SYS_DUP      "0x0D"
+#define SYS_DUP2     "0x0E"
+#define SYS_DUP3     "0x0F"
+#define SYS_FCNTL    "0x10"
+#define SYS_PIPE     "0x11"
+#define SYS_KILL        "0x12"
+#define SYS_SIGACTION   "0x13"
+#define SYS_SIGPROCMASK "0x14"
+#define SYS_SIGRETURN   "0x15"
+#define SYS_GETPID      "0x16"
+#define SYS_TCSETPGRP   "0x17"
+#define SYS_NICE        "0x18"
+#define SYS_GETPRIORITY "0x19"
+#define SYS_SETPRIORITY "0x1A"
+#define SYS_SCHED_SETSCHEDULER "0x1B"
+#define SYS_SCHED_GETSCHEDULER "0x1C"
+#define SYS_NANOSLEEP   "0x1D"
+#define SYS_CLOCK_NANOSLEEP "0x1E"
+#define SYS_ALARM       "0x1F"
+#define SYS_UPTIME      "0x20"
+#define SYS_CLONE       "0x21"
+#define SYS_GETTID      "0x22"
+#define SYS_EXIT_THREAD "0x23"
+#define SYS_FUTEX       "0x24"
+#define SYS_MMAP        "0x25"
+#define SYS_MUNMAP      "0x26"
+#define SYS_MPROTECT    "0x27"
+#define SYS_FRAMES      "0x28"
+
+#define EPERM         1
+#define ENOENT        2
+#define ESRCH         3
+#define EINTR         4
+#define E2BIG         7
+#define ENOEXEC       8
+#define EBADF         9
+#define ECHILD       10
+#define EAGAIN       11
+#define ENOMEM       12
+#define EACCES       13
+#define EFAULT       14
+#define EEXIST       17
+#define ENODEV       19
+#define ENOTDIR      20
+#define EISDIR       21
+#define EINVAL       22
+#define EMFILE       24
+#define EFBIG        27
+#define ENOSPC       28
+#define EPIPE        32
+#define ERANGE       34
+#define ENAMETOOLONG 36
+#define ENOSYS       38
+#define ELOOP        40
+#define ETIMEDOUT   110
 
 #define STDIN_FILENO 0
 #define STDOUT_FILENO 1
@@ -19,6 +74,86 @@
 #define O_WRONLY    2
 #define O_RDWR      4
 #define O_DIRECTORY 8
+#define O_CLOEXEC   16
+#define O_NONBLOCK  32
+
+#define F_DUPFD         0
+#define F_GETFD         1
+#define F_SETFD         2
+#define F_GETFL         3
+#define F_SETFL         4
+#define F_DUPFD_CLOEXEC 1030
+
+#define FD_CLOEXEC 1
+
+#define SIGINT    2
+#define SIGILL    4
+#define SIGTRAP   5
+#define SIGBUS    7
+#define SIGFPE    8
+#define SIGKILL   9
+#define SIGUSR1   10
+#define SIGSEGV   11
+#define SIGUSR2   12
+#define SIGPIPE   13
+#define SIGALRM   14
+#define SIGTERM   15
+#define SIGCHLD   17
+#define SIGCONT   18
+#define SIGSTOP   19
+#define SIGTSTP   20
+
+#define SIG_DFL ((sighandler_t)0)
+#define SIG_IGN ((sighandler_t)1)
+
+#define SIG_BLOCK   0
+#define SIG_UNBLOCK 1
+#define SIG_SETMASK 2
+
+#define SA_NODEFER   0x40000000
+#define SA_RESETHAND 0x80000000
+
+#define WNOHANG 1
+
+#define WIFEXITED(s)   (((s) & 0x7f) == 0)
+#define WEXITSTATUS(s) (((s) >> 8) & 0xff)
+#define WIFSIGNALED(s) (((s) & 0x7f) != 0)
+#define WTERMSIG(s)    ((s) & 0x7f)
+
+#define PRIO_PROCESS 0
+
+#define SCHED_OTHER 0
+#define SCHED_FIFO  1
+
+#define CLOCK_REALTIME  0
+#define CLOCK_MONOTONIC 1
+#define TIMER_ABSTIME   1
+
+#define CLONE_VM             0x0000100
+#define CLONE_FS             0x0000200
+#define CLONE_FILES          0x0000400
+#define CLONE_SIGHAND        0x0000800
+#define CLONE_THREAD         0x0010000
+#define CLONE_SETTLS         0x0080000
+#define CLONE_PARENT_SETTID  0x0100000
+#define CLONE_CHILD_CLEARTID 0x0200000
+
+#define FUTEX_WAIT         0
+#define FUTEX_WAKE         1
+#define FUTEX_REQUEUE      3
+#define FUTEX_PRIVATE_FLAG 128
+
+#define PROT_NONE  0
+#define PROT_READ  1
+#define PROT_WRITE 2
+#define PROT_EXEC  4
+
+#define MAP_SHARED    0x01
+#define MAP_PRIVATE   0x02
+#define MAP_FIXED     0x10
+#define MAP_ANONYMOUS 0x20
+
+#define MAP_FAILED ((void *)-1)
 
 #define NULL (void *)0
 
@@ -30,23 +165,83 @@ typedef struct DIR {
     void *buffer;
 } DIR;
 
+typedef void (*sighandler_t)(int);
+typedef unsigned long sigset_t;
+
+struct sigaction {
+    sighandler_t sa_handler;
+    unsigned long sa_flags;
+    void (*sa_restorer)(void);
+    sigset_t sa_mask;
+};
+
+struct timespec {
+    long tv_sec;
+    long tv_nsec;
+};
+
+struct sched_param {
+    int sched_priority;
+};
+
 struct dirent {
     unsigned int d_ino;
     char name[12];
 };
 
+// set when a system call returns -1
+extern int errno;
+
+// the environment the program was started with
+extern char **environ;
+
 // system call
 int fork();
+int execve(const char *, char *const argv[], char *const envp[]);
 int exec(const char *, char *const argv[]);
 int open(const char *, int flags);
+int close(int fd);
 int write(int fd, const void *buf, int count);
 int  read(int fd, void *buf, int count);
-int waitpid(int pid, int *wstatus);
+int waitpid(int pid, int *wstatus, int options);
+void exit(int status);
 int getdents(unsigned int, struct dirent *, unsigned int);
 void *sbrk(size_t);
 char *getcwd(char *, size_t);
 int mkdir(char *);
 int chdir(char *);
+int dup(int oldfd);
+int dup2(int oldfd, int newfd);
+int dup3(int oldfd, int newfd, int flags);
+int fcntl(int fd, int cmd, int arg);
+int pipe(int pipefd[2]);
+int pipe2(int pipefd[2], int flags);
+int kill(int pid, int sig);
+int sigaction(int signum, const struct sigaction *act, struct sigaction *oldact);
+int sigprocmask(int how, const sigset_t *set, sigset_t *oldset);
+int getpid();
+int tcsetpgrp(int fd, int pid);
+int nice(int inc);
+int getpriority(int which, int who);
+int setpriority(int which, int who, int prio);
+int sched_setscheduler(int pid, int policy, const struct sched_param *param);
+int sched_getscheduler(int pid);
+int nanosleep(const struct timespec *req, struct timespec *rem);
+int clock_nanosleep(int clockid, int flags, const struct timespec *req, struct timespec *rem);
+unsigned int alarm(unsigned int seconds);
+unsigned int sleep(unsigned int seconds);
+int uptime(struct timespec *uptime, struct timespec *idle);
+// the frames of memory user space and the page cache have
+int frames();
+int clone(int (*fn)(void *), void *stack, int flags, void *arg, int *ptid, void *tls, int *ctid);
+int gettid();
+void exit_thread(int status);
+// the timeout of FUTEX_WAIT is relative, FUTEX_REQUEUE takes the number to requeue in its place
+int futex(int *uaddr, int op, int val, const struct timespec *timeout, int *uaddr2);
+// anonymous memory is private only, fd and offset are ignored for it
+void *mmap(void *addr, size_t length, int prot, int flags, int fd, long offset);
+int munmap(void *addr, size_t length);
+int mprotect(void *addr, size_t length, int prot);
 
 
 // library
@@ -57,4 +252,6 @@ void *memset(void *s, int c, size_t n);
 DIR *fdopendir(int);
 struct dirent *readdir(DIR *);
 void *malloc(size_t);
-void free(void *);
\ No newline at end of file
+void free(void *);
+sighandler_t signal(int signum, sighandler_t handler);
+void *get_tls(void);
\ No newline at end of file
@@ -32,7 +32,7 @@ void *malloc(size_t size)
     // can't find enough space
     // ask for more from OS
     if (res == NULL) {
-        if ((res = (int *)sbrk(size)) == NULL) {
+        if ((res = (int *)sbrk(size)) == (void *)-1) {
             return NULL;
         }
     }
@@ -0,0 +1,82 @@
+#include "libc.h"
+
+#define PAGE 4096
+
+static char *mem;
+
+static void touch(char *p)
+{
+    *(volatile char *)p = 1;
+}
+
+static void peek(char *p)
+{
+    (void)*(volatile char *)p;
+}
+
+// the child does it, returns the signal that killed it, 0 if none
+static int try(void (*fn)(char *), char *p)
+{
+    int pid = fork();
+    if (pid == 0) {
+        fn(p);
+        exit(0);
+    }
+
+    int wstatus;
+    waitpid(pid, &wstatus, 0);
+    return WIFSIGNALED(wstatus) ? WTERMSIG(wstatus) : 0;
+}
+
+int main(int argc, char *argv[])
+{
+    mem = mmap(NULL, 4 * PAGE, PROT_READ | PROT_WRITE, MAP_PRIVATE | MAP_ANONYMOUS, -1, 0);
+    if (mem == MAP_FAILED) {
+        printf("mmap failed, errno %d\n", errno);
+        return -1;
+    }
+
+    // the pages come zeroed the first time they are touched
+    int sum = 0;
+    for (int i = 0; i < 4 * PAGE; i++) {
+        sum += mem[i];
+        mem[i] = i % 7;
+    }
+    printf("zeroed: %d\n", sum == 0);
+
+    mprotect(mem + PAGE, PAGE, PROT_READ);
+    printf("read-only, read: signal %d\n", try(peek, mem + PAGE));
+    printf("read-only, write: signal %d\n", try(touch, mem + PAGE));
+
+    mprotect(mem + 2 * PAGE, PAGE, PROT_NONE);
+    printf("none, read: signal %d\n", try(peek, mem + 2 * PAGE));
+    // the kernel can't get at it either
+    int ret = write(STDOUT_FILENO, mem + 2 * PAGE, 1);
+    printf("none, write(2): %d, errno %d\n", ret, errno);
+
+    munmap(mem, PAGE);
+    printf("unmapped, read: signal %d\n", try(peek, mem));
+    printf("still there: %d\n", mem[3 * PAGE + 1]);
+
+    munmap(mem + PAGE, 3 * PAGE);
+
+    // a private mapping of this very program, the writes stay here
+    int fd = open("/mmap", O_RDONLY);
+    char *elf = mmap(NULL, PAGE, PROT_READ | PROT_WRITE, MAP_PRIVATE, fd, 0);
+    if (elf == MAP_FAILED) {
+        printf("file mmap failed, errno %d\n", errno);
+        return -1;
+    }
+    printf("file: %d\n", elf[0] == 0x7f && elf[1] == 'E' && elf[2] == 'L' && elf[3] == 'F');
+    elf[1] = 'X';
+
+    char magic[4];
+    read(fd, magic, 4);
+    printf("file untouched: %d\n", magic[1] == 'E');
+
+    ret = (long)mmap(NULL, PAGE, PROT_READ | PROT_WRITE, MAP_SHARED, fd, 0);
+    printf("shared on read-only fd: %d, errno %d\n", ret, errno);
+    close(fd);
+    munmap(elf, PAGE);
+    return 0;
+}
@@ -2,15 +2,47 @@
 
 // shell
 
-int main()
+static void on_interrupt(int sig)
 {
+    // Ctrl-C only interrupts the running command
+}
+
+// a line of a script without the newline, NULL at the end of the script
+static char *read_line(char *s, int size, int fd)
+{
+    int len = 0, n = 0;
+    char c;
+    while (len < size - 1 && (n = read(fd, &c, 1)) == 1 && c != '\n')
+        s[len++] = c;
+    s[len] = '\0';
+    return (n == 1 || len > 0) ? s : NULL;
+}
+
+int main(int argc, char *argv[])
+{
+    // a script, run as `sh script` or by its `#!/sh` line
+    int fd = STDIN_FILENO;
+    if (argc > 1 && (fd = open(argv[1], O_RDONLY)) == -1) {
+        printf("%s: No such file or directory\n", argv[1]);
+        return -1;
+    }
+
+    signal(SIGINT, on_interrupt);
+    tcsetpgrp(STDIN_FILENO, getpid());
+
     for(;;) {
-        fputs("$ ", STDOUT_FILENO);
+        if (fd == STDIN_FILENO)
+            fputs("$ ", STDOUT_FILENO);
         // read command
         char cmd[32];
-        
-        if (fgets(cmd, 32, STDIN_FILENO) != NULL) {
-            if(cmd[0] == '\0')
+
+        char *line = fd == STDIN_FILENO ? fgets(cmd, 32, fd) : read_line(cmd, 32, fd);
+        if (line == NULL && fd != STDIN_FILENO)
+            return 0;
+
+        if (line != NULL) {
+            // the `#!` line is a comment too
+            if(cmd[0] == '\0' || cmd[0] == '#')
                 continue;
 
             if(cmd[0] == 'c' && cmd[1] == 'd' && cmd[2] == ' ') {
@@ -53,7 +85,10 @@ int main()
                 return -1;
             }
 
-            waitpid(pid, NULL);
+            // the command gets Ctrl-C while it's running
+            tcsetpgrp(STDIN_FILENO, pid);
+            waitpid(pid, NULL, 0);
+            tcsetpgrp(STDIN_FILENO, getpid());
         }
     }
 }
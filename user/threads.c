@@ -0,0 +1,53 @@
+#include "libc.h"
+
+#define NTHREAD    4
+#define STACK_SIZE 4096
+
+struct thread {
+    int id;
+    int tid;
+    int sum;
+};
+
+static struct thread threads[NTHREAD];
+static char *stacks[NTHREAD];
+
+static int worker(void *arg)
+{
+    // every thread sees its own struct through TPIDR_EL0
+    struct thread *self = get_tls();
+    for (int i = 1; i <= 100 * (self->id + 1); i++) {
+        self->sum += i;
+    }
+    printf("thread %d: tid %d, pid %d\n", self->id, gettid(), getpid());
+    return 0;
+}
+
+int main(int argc, char *argv[])
+{
+    int flags = CLONE_VM | CLONE_FS | CLONE_FILES | CLONE_SIGHAND | CLONE_THREAD |
+                CLONE_SETTLS | CLONE_PARENT_SETTID | CLONE_CHILD_CLEARTID;
+
+    for (int i = 0; i < NTHREAD; i++) {
+        stacks[i] = malloc(STACK_SIZE);
+        threads[i].id = i;
+    }
+
+    for (int i = 0; i < NTHREAD; i++) {
+        struct thread *t = &threads[i];
+        if (clone(worker, stacks[i] + STACK_SIZE, flags, NULL, &t->tid, t, &t->tid) < 0) {
+            printf("clone failed\n");
+            return -1;
+        }
+    }
+
+    // the kernel clears tid and wakes us up when the thread exits
+    for (int i = 0; i < NTHREAD; i++) {
+        int tid;
+        while ((tid = threads[i].tid) != 0) {
+            futex(&threads[i].tid, FUTEX_WAIT, tid, NULL, NULL);
+        }
+        printf("thread %d: sum %d\n", i, threads[i].sum);
+    }
+    return 0;
+}
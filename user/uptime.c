@@ -0,0 +1,13 @@
+#include "libc.h"
+
+int main(int argc, char *argv[]) {
+    struct timespec up, idle;
+    if (uptime(&up, &idle) < 0) {
+        printf("can't get uptime\n");
+        return -1;
+    }
+
+    // the idle time is summed over the cpus, like /proc/uptime
+    printf("up %d s, idle %d s\n", (int)up.tv_sec, (int)idle.tv_sec);
+    return 0;
+}